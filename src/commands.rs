@@ -0,0 +1,267 @@
+use lazuli_vm::args_setup;
+use lazuli_vm::object::cons_list::ConsList;
+use lazuli_vm::object::{Callable, Node};
+use lazuli_vm::vm::VM;
+
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// Paths of commands found on $PATH. The cache is dropped when PATH changes
+// or by rehash. Names of builtins and symbols defined by the user are recorded
+// as they're defined since the VM doesn't provide a way to list symbols.
+thread_local! {
+    static NAMES: RefCell<BTreeSet<String>> = const { RefCell::new(BTreeSet::new()) };
+    static HASH: RefCell<HashMap<String, PathBuf>> = RefCell::new(HashMap::new());
+    static HASHED_PATH: RefCell<Option<OsString>> = const { RefCell::new(None) };
+}
+
+const MAX_SUGGESTIONS: usize = 3;
+
+pub fn add_name(name: &str) {
+    NAMES.with(|n| n.borrow_mut().insert(name.to_owned()));
+}
+
+fn clear_hash() {
+    HASH.with(|h| h.borrow_mut().clear());
+}
+
+/// Names of builtins, user defined symbols, and executables on $PATH.
+pub fn command_names() -> Vec<String> {
+    let mut names: Vec<String> = NAMES.with(|n| n.borrow().iter().cloned().collect());
+
+    if let Some(paths) = env::var_os("PATH") {
+        for dir in env::split_paths(&paths) {
+            let entries = match fs::read_dir(dir) {
+                Ok(e) => e,
+                Err(_) => continue,
+            };
+
+            for entry in entries.filter_map(Result::ok) {
+                if is_executable(&entry.path()) {
+                    names.push(entry.file_name().to_string_lossy().into_owned());
+                }
+            }
+        }
+    }
+
+    names
+}
+
+// Find an executable in the directories of a PATH value.
+fn find_command(name: &str, paths: &OsStr) -> Option<PathBuf> {
+    env::split_paths(paths)
+        .map(|dir| dir.join(name))
+        .find(|path| is_executable(path))
+}
+
+// Whether a path is a file with an execute bit set.
+fn is_executable(path: &Path) -> bool {
+    match fs::metadata(path) {
+        Ok(m) => m.is_file() && m.permissions().mode() & 0o111 != 0,
+        Err(_) => false,
+    }
+}
+
+/// Find the path of a command, names containing a slash are used as is.
+pub fn resolve(name: &str) -> Option<PathBuf> {
+    if name.contains('/') {
+        let path = PathBuf::from(name);
+        return if is_executable(&path) {
+            Some(path)
+        } else {
+            None
+        };
+    }
+
+    let path_var = env::var_os("PATH");
+    if HASHED_PATH.with(|p| p.replace(path_var.clone())) != path_var {
+        clear_hash();
+    }
+
+    // A hashed command may have been removed since it was found
+    let hashed = HASH.with(|h| h.borrow().get(name).cloned());
+    if let Some(path) = hashed.filter(|p| is_executable(p)) {
+        return Some(path);
+    }
+
+    let path = find_command(name, path_var.as_deref()?)?;
+    HASH.with(|h| h.borrow_mut().insert(name.to_owned(), path.clone()));
+    Some(path)
+}
+
+/// The program to run for a command name, its path if it's found.
+pub fn program(name: &str) -> OsString {
+    match resolve(name) {
+        Some(path) => path.into_os_string(),
+        None => name.into(),
+    }
+}
+
+/// Create a command for a name using the hashed path. The command still sees
+/// the name as given in argv[0].
+pub fn new_command(name: &str) -> Command {
+    let mut cmd = Command::new(program(name));
+    cmd.arg0(name);
+    cmd
+}
+
+/// Create a command for a name found on a PATH other than the shell's, such
+/// as one set by with-env. It isn't hashed, there's nothing to find without
+/// a PATH.
+pub fn new_command_in(name: &str, path_var: Option<&OsStr>) -> Command {
+    let program = if name.contains('/') {
+        resolve(name)
+    } else {
+        path_var.and_then(|p| find_command(name, p))
+    };
+
+    let mut cmd = Command::new(program.map_or_else(|| name.into(), PathBuf::into_os_string));
+    cmd.arg0(name);
+    cmd
+}
+
+/// Error message for a command that couldn't be found, with suggestions for
+/// commands with similar names.
+pub fn not_found_error(name: &str) -> String {
+    let mut candidates: Vec<(usize, String)> = command_names()
+        .into_iter()
+        .filter(|c| c != name)
+        .map(|c| (edit_distance(name, &c), c))
+        .filter(|(d, _)| *d <= max_distance(name))
+        .collect();
+    candidates.sort();
+    candidates.dedup();
+
+    if candidates.is_empty() {
+        return format!("Command not found {}", name);
+    }
+
+    let suggestions: Vec<String> = candidates
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, c)| c)
+        .collect();
+    format!(
+        "Command not found {}, did you mean {}?",
+        name,
+        suggestions.join(", ")
+    )
+}
+
+// Short names only allow a single typo.
+fn max_distance(name: &str) -> usize {
+    if name.chars().count() <= 4 {
+        1
+    } else {
+        2
+    }
+}
+
+// Levenshtein distance counting a swap of adjacent characters as one edit.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut rows = vec![vec![0; b.len() + 1]; a.len() + 1];
+
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in rows[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut d = (rows[i - 1][j] + 1)
+                .min(rows[i][j - 1] + 1)
+                .min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d = d.min(rows[i - 2][j - 2] + 1);
+            }
+            rows[i][j] = d;
+        }
+    }
+
+    rows[a.len()][b.len()]
+}
+
+fn callable_kind(func: &Callable) -> Option<&'static str> {
+    match func {
+        Callable::None => None,
+        Callable::Func(_) => Some("a function"),
+        Callable::Macro(_) => Some("a macro"),
+        _ => Some("a shell builtin"),
+    }
+}
+
+pub fn shell_rehash(_vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    args_setup!(args, "rehash", ==, 0);
+    clear_hash();
+    Ok(Node::Empty)
+}
+
+pub fn shell_which(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let args = args_setup!(args, "which", ==, 1);
+
+    let name = format!("{}", vm.eval(args[0])?);
+    match resolve(&name) {
+        Some(path) => Ok(Node::from_string(path.to_string_lossy().into_owned())),
+        None => Ok(Node::Empty),
+    }
+}
+
+pub fn shell_type(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let args = args_setup!(args, "type", ==, 1);
+
+    let name = format!("{}", vm.eval(args[0])?);
+
+    // Symbols are found before external commands
+    let sym = vm.symbols.borrow().get_symbol(&name);
+    let kind = {
+        let sym = sym.borrow();
+        match (&sym.function, sym.value()) {
+            (Callable::None, Node::Function(f)) => callable_kind(&f),
+            (f, _) => callable_kind(f),
+        }
+    };
+
+    let desc = match kind {
+        Some(kind) => format!("{} is {}", name, kind),
+        None => match resolve(&name) {
+            Some(path) => format!("{} is {}", name, path.display()),
+            None => return Err(format!("type {} not found", name)),
+        },
+    };
+    Ok(Node::from_string(desc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("ls", "ls"), 0);
+        assert_eq!(edit_distance("sl", "ls"), 1);
+        assert_eq!(edit_distance("gti", "git"), 1);
+        assert_eq!(edit_distance("grp", "grep"), 1);
+        assert_eq!(edit_distance("cargoo", "cargo"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+    }
+
+    #[test]
+    fn short_names_allow_one_typo() {
+        assert_eq!(max_distance("git"), 1);
+        assert_eq!(max_distance("make"), 1);
+        assert_eq!(max_distance("cargo"), 2);
+    }
+}
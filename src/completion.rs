@@ -0,0 +1,149 @@
+use crate::terminal::Completer;
+use crate::{call_function, commands};
+
+use lazuli_vm::args_setup;
+use lazuli_vm::object::cons_list::ConsList;
+use lazuli_vm::object::Node;
+use lazuli_vm::vm::VM;
+use shellexpand::tilde;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+
+// Completion functions added by defcompletion, by command name.
+thread_local! {
+    static COMPLETIONS: RefCell<HashMap<String, Node>> = RefCell::new(HashMap::new());
+}
+
+const GET_KEY_KEYWORDS: [&str; 5] = [
+    ":pipestatus",
+    ":stage-stderr",
+    ":status",
+    ":stderr",
+    ":stdout",
+];
+
+pub struct ShellCompleter<'a> {
+    vm: &'a mut VM,
+}
+
+impl<'a> ShellCompleter<'a> {
+    pub fn new(vm: &'a mut VM) -> Self {
+        ShellCompleter { vm }
+    }
+}
+
+impl<'a> Completer for ShellCompleter<'a> {
+    fn complete(&mut self, line: &str, word_start: usize) -> Vec<String> {
+        let word = &line[word_start..];
+        let form = current_form(&line[..word_start]);
+        let command = form.split_whitespace().next();
+
+        let candidates = match command {
+            None if !word.contains('/') => complete_command(word),
+            Some("get-key") if word.is_empty() || word.starts_with(':') => {
+                GET_KEY_KEYWORDS.iter().map(|k| (*k).to_owned()).collect()
+            }
+            Some(cmd) => {
+                let func = COMPLETIONS.with(|c| c.borrow().get(cmd).cloned());
+                match func {
+                    Some(func) => self.complete_lisp(&func, word, line),
+                    None => complete_path(word),
+                }
+            }
+            None => complete_path(word),
+        };
+
+        candidates
+            .into_iter()
+            .filter(|c| c.starts_with(word))
+            .collect()
+    }
+}
+
+impl<'a> ShellCompleter<'a> {
+    fn complete_lisp(&mut self, func: &Node, word: &str, line: &str) -> Vec<String> {
+        let args = vec![
+            Node::from_string(word.to_owned()),
+            Node::from_string(line.to_owned()),
+        ];
+
+        match call_function(self.vm, func, args) {
+            Ok(Node::List(l)) => l.iter().map(|n| format!("{}", n)).collect(),
+            Ok(Node::String(s)) => vec![s],
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Text of the innermost unclosed form. The shell adds the outer parentheses so
+/// a line without any is a single form.
+fn current_form(line: &str) -> &str {
+    let mut depth = 0;
+    let mut in_string = false;
+
+    for (i, c) in line.char_indices().rev() {
+        match c {
+            '"' => in_string = !in_string,
+            ')' if !in_string => depth += 1,
+            '(' if !in_string => {
+                if depth == 0 {
+                    return &line[i + 1..];
+                }
+                depth -= 1;
+            }
+            _ => {}
+        }
+    }
+
+    line
+}
+
+fn complete_command(word: &str) -> Vec<String> {
+    commands::command_names()
+        .into_iter()
+        .filter(|name| name.starts_with(word))
+        .collect()
+}
+
+fn complete_path(word: &str) -> Vec<String> {
+    let (dir, prefix) = match word.rfind('/') {
+        Some(i) => (&word[..=i], &word[i + 1..]),
+        None => ("", word),
+    };
+
+    let search_dir = if dir.is_empty() {
+        ".".to_owned()
+    } else {
+        tilde(dir).into_owned()
+    };
+
+    let entries = match fs::read_dir(search_dir) {
+        Ok(e) => e,
+        Err(_) => return Vec::new(),
+    };
+
+    entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+
+            let suffix = if entry.path().is_dir() { "/" } else { "" };
+            Some(format!("{}{}{}", dir, name, suffix))
+        })
+        .collect()
+}
+
+pub fn shell_defcompletion(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let args = args_setup!(args, "defcompletion", ==, 2);
+
+    let command = format!("{}", vm.eval(args[0])?);
+    let func = vm.eval(args[1])?;
+    COMPLETIONS.with(|c| c.borrow_mut().insert(command, func));
+
+    Ok(Node::Empty)
+}
@@ -0,0 +1,211 @@
+use crate::commands;
+use crate::environment;
+use crate::hooks;
+use crate::options;
+
+use lazuli_vm::args_setup;
+use lazuli_vm::object::cons_list::ConsList;
+use lazuli_vm::object::Node;
+use lazuli_vm::vm::VM;
+use path_absolutize::*;
+use shellexpand::tilde;
+
+use std::cell::RefCell;
+use std::env;
+use std::path::{Path, PathBuf};
+
+// Directories saved by pushd, the most recent is last. The current directory
+// isn't part of the stack.
+thread_local! {
+    static DIR_STACK: RefCell<Vec<PathBuf>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Change the working directory, keep PWD and OLDPWD in sync, and run the
+/// chpwd hooks.
+pub fn change_dir(vm: &mut VM, path: &Path) -> Result<(), String> {
+    let old_pwd = env::current_dir().unwrap_or_default();
+    env::set_current_dir(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    let pwd = env::current_dir().unwrap_or_default();
+    environment::set_var(vm, "OLDPWD", &old_pwd.to_string_lossy());
+    environment::set_var(vm, "PWD", &pwd.to_string_lossy());
+
+    hooks::run_hooks(
+        vm,
+        "chpwd",
+        vec![
+            Node::from_string(old_pwd.to_string_lossy().into_owned()),
+            Node::from_string(pwd.to_string_lossy().into_owned()),
+        ],
+    );
+    Ok(())
+}
+
+// Evaluate an argument that should be a path.
+fn path_arg(vm: &mut VM, arg: &Node, name: &str) -> Result<String, String> {
+    match vm.eval(arg)? {
+        Node::String(s) => Ok(s),
+        Node::Symbol(sym) => match sym.borrow().value() {
+            Node::String(s) => Ok(s),
+            _ => Err(format!("{} expected a string", name)),
+        },
+        _ => Ok("".to_owned()),
+    }
+}
+
+// Expand a leading tilde and make the path absolute.
+fn absolute_path(path_str: &str) -> PathBuf {
+    let path_str = tilde(path_str);
+    let path = Path::new(path_str.as_ref());
+    if path.is_absolute() {
+        path.absolutize().unwrap().to_path_buf()
+    } else {
+        let pwd = env::current_dir().unwrap_or_default();
+        pwd.join(path).absolutize().unwrap().to_path_buf()
+    }
+}
+
+// Search the CDPATH symbol, a list or colon separated string, for a relative
+// directory. An empty entry is the current directory. The flag is set when the
+// directory was found through an entry other than the current directory.
+fn search_cdpath(vm: &mut VM, path_str: &str) -> Option<(PathBuf, bool)> {
+    let relative = [".", "..", "~"]
+        .iter()
+        .all(|prefix| path_str != *prefix && !path_str.starts_with(&format!("{}/", prefix)));
+    if !relative || Path::new(path_str).is_absolute() {
+        return None;
+    }
+
+    let cdpath = vm.symbols.borrow().get_symbol("CDPATH");
+    let dirs: Vec<String> = match cdpath.borrow().value() {
+        Node::String(s) => s.split(':').map(str::to_owned).collect(),
+        Node::List(l) => l.iter().map(|n| format!("{}", n)).collect(),
+        _ => return None,
+    };
+
+    dirs.iter()
+        .map(|dir| {
+            let current = dir.is_empty() || dir == ".";
+            let dir = if dir.is_empty() { "." } else { dir };
+            (absolute_path(dir).join(path_str), !current)
+        })
+        .find(|(path, _)| path.is_dir())
+}
+
+/// With the autocd option, change to `name` if it's a directory and not a
+/// command. Returns true if the directory was changed.
+pub fn autocd(vm: &mut VM, name: &str) -> Result<bool, String> {
+    if !options::is_set("autocd") || (!name.contains('/') && commands::resolve(name).is_some()) {
+        return Ok(false);
+    }
+
+    let path = absolute_path(name);
+    if !path.is_dir() {
+        return Ok(false);
+    }
+
+    change_dir(vm, &path)?;
+    Ok(true)
+}
+
+fn home_dir() -> Result<PathBuf, String> {
+    env::var_os("HOME")
+        .map(PathBuf::from)
+        .or_else(dirs::home_dir)
+        .ok_or_else(|| "cd HOME not set".to_owned())
+}
+
+/// The directory stack as a list of strings, starting with the current directory.
+fn stack_list() -> Node {
+    let cwd = env::current_dir().unwrap_or_default();
+    DIR_STACK.with(|s| {
+        // ConsList::append adds to the front so build the list from the bottom up
+        let list = s
+            .borrow()
+            .iter()
+            .chain(std::iter::once(&cwd))
+            .fold(ConsList::new(), |list, dir| {
+                list.append(Node::from_string(dir.to_string_lossy().into_owned()))
+            });
+        Node::List(list)
+    })
+}
+
+pub fn shell_cd(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let args = args_setup!(args);
+    if args.len() > 1 {
+        return Err("cd expected at most 1 argument".to_owned());
+    }
+
+    let path = match args.first() {
+        None => home_dir()?,
+        Some(arg) => match path_arg(vm, arg, "cd")?.as_str() {
+            // Go back to the previous directory
+            "-" => {
+                let old_pwd =
+                    env::var_os("OLDPWD").ok_or_else(|| "cd OLDPWD not set".to_owned())?;
+                println!("{}", Path::new(&old_pwd).display());
+                PathBuf::from(old_pwd)
+            }
+            path => match search_cdpath(vm, path) {
+                Some((found, print)) => {
+                    if print {
+                        println!("{}", found.display());
+                    }
+                    found
+                }
+                None => absolute_path(path),
+            },
+        },
+    };
+
+    change_dir(vm, &path)?;
+    Ok(Node::Empty)
+}
+
+pub fn shell_pushd(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let args = args_setup!(args);
+    if args.len() > 1 {
+        return Err("pushd expected at most 1 argument".to_owned());
+    }
+
+    let cwd = env::current_dir().map_err(|e| format!("{}", e))?;
+    match args.first() {
+        // Swap the current directory with the top of the stack
+        None => {
+            let top = DIR_STACK
+                .with(|s| s.borrow().last().cloned())
+                .ok_or_else(|| "pushd no other directory".to_owned())?;
+            change_dir(vm, &top)?;
+            DIR_STACK.with(|s| {
+                if let Some(last) = s.borrow_mut().last_mut() {
+                    *last = cwd;
+                }
+            });
+        }
+        Some(arg) => {
+            let path = absolute_path(&path_arg(vm, arg, "pushd")?);
+            change_dir(vm, &path)?;
+            DIR_STACK.with(|s| s.borrow_mut().push(cwd));
+        }
+    }
+
+    Ok(stack_list())
+}
+
+pub fn shell_popd(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    args_setup!(args, "popd", ==, 0);
+
+    let top = DIR_STACK
+        .with(|s| s.borrow().last().cloned())
+        .ok_or_else(|| "popd directory stack empty".to_owned())?;
+    change_dir(vm, &top)?;
+    DIR_STACK.with(|s| s.borrow_mut().pop());
+
+    Ok(stack_list())
+}
+
+pub fn shell_dirs(_vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    args_setup!(args, "dirs", ==, 0);
+    Ok(stack_list())
+}
@@ -0,0 +1,259 @@
+use crate::{commands, is_interactive, is_keyword, shell_call};
+
+use lazuli_vm::args_setup;
+use lazuli_vm::object::cons_list::ConsList;
+use lazuli_vm::object::{Node, Symbol};
+use lazuli_vm::vm::VM;
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::env;
+use std::ffi::OsStr;
+use std::process::Command;
+
+// Names of symbols exported to the environment of child processes. The process
+// environment is kept in sync with their values so children inherit them.
+// Overrides from with-env forms apply to every command evaluated in their body
+// without changing the shell, inner forms take precedence over outer ones.
+thread_local! {
+    static EXPORTED: RefCell<BTreeSet<String>> = const { RefCell::new(BTreeSet::new()) };
+    static OVERRIDES: RefCell<Vec<Override>> = const { RefCell::new(Vec::new()) };
+}
+
+enum Override {
+    Set(String, String),
+    Remove(String),
+    Clear,
+}
+
+/// Changes to the inherited environment for a command after combining all
+/// active with-env forms. A None value removes the variable.
+#[derive(Default)]
+pub struct EnvChanges {
+    clear: bool,
+    vars: BTreeMap<String, Option<String>>,
+}
+
+impl EnvChanges {
+    pub fn current() -> Self {
+        OVERRIDES.with(|o| {
+            let mut changes = EnvChanges::default();
+
+            for o in o.borrow().iter() {
+                match o {
+                    Override::Set(key, value) => {
+                        changes.vars.insert(key.clone(), Some(value.clone()));
+                    }
+                    Override::Remove(key) => {
+                        changes.vars.insert(key.clone(), None);
+                    }
+                    Override::Clear => {
+                        changes.clear = true;
+                        changes.vars.clear();
+                    }
+                }
+            }
+
+            changes
+        })
+    }
+
+    /// Create a command with the changes applied. When PATH is changed the
+    /// program is found on the new PATH rather than the shell's.
+    pub fn command(&self, name: &str) -> Command {
+        let mut cmd = if self.clear || self.vars.contains_key("PATH") {
+            let path = self.vars.get("PATH").cloned().flatten();
+            commands::new_command_in(name, path.as_deref().map(OsStr::new))
+        } else {
+            commands::new_command(name)
+        };
+        self.apply(&mut cmd);
+        cmd
+    }
+
+    fn apply(&self, cmd: &mut Command) {
+        if self.clear {
+            cmd.env_clear();
+        }
+        for (key, value) in self.vars.iter() {
+            match value {
+                Some(value) => cmd.env(key, value),
+                None => cmd.env_remove(key),
+            };
+        }
+    }
+}
+
+/// Add a symbol for every environment variable, all of them start exported.
+pub fn init(vm: &mut VM) {
+    for (key, value) in env::vars() {
+        vm.add_symbol(Symbol::with_value(&key, Node::from_string(value)).into_ref());
+        EXPORTED.with(|e| e.borrow_mut().insert(key));
+    }
+}
+
+fn is_exported(name: &str) -> bool {
+    EXPORTED.with(|e| e.borrow().contains(name))
+}
+
+/// Set a symbol and export it.
+pub fn set_var(vm: &mut VM, key: &str, value: &str) {
+    vm.add_symbol(Symbol::with_value(key, Node::from_string(value.to_owned())).into_ref());
+    export(vm, key);
+}
+
+fn export(vm: &mut VM, name: &str) {
+    EXPORTED.with(|e| e.borrow_mut().insert(name.to_owned()));
+    sync_var(vm, name);
+}
+
+/// Copy the value of an exported symbol to the process environment. Called
+/// whenever a symbol may have changed, nothing is done if it isn't exported.
+pub fn sync_var(vm: &mut VM, name: &str) {
+    if !is_exported(name) {
+        return;
+    }
+
+    let sym = vm.symbols.borrow().get_symbol(name);
+    let value = format!("{}", sym.borrow().value());
+    if env::var(name).ok().as_deref() != Some(value.as_str()) {
+        env::set_var(name, value);
+    }
+}
+
+// The symbol named by an export argument, such as (export 'PATH).
+fn symbol_name(vm: &mut VM, arg: &Node, name: &str) -> Result<String, String> {
+    match vm.eval(arg)? {
+        Node::Symbol(s) => Ok(s.borrow().name().to_owned()),
+        _ => Err(format!("{} requires a Symbol as the first argument", name)),
+    }
+}
+
+pub fn shell_export(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let args = args_setup!(args, "export", >=, 1);
+    if args.len() > 2 {
+        return Err("export expected at most 2 arguments".to_owned());
+    }
+
+    let name = symbol_name(vm, args[0], "export")?;
+    match args.get(1) {
+        Some(arg) => {
+            let value = vm.eval(arg)?;
+            vm.add_symbol(Symbol::with_value(&name, value).into_ref());
+            export(vm, &name);
+        }
+        None => export(vm, &name),
+    }
+    Ok(Node::Empty)
+}
+
+pub fn shell_unexport(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let args = args_setup!(args, "unexport", ==, 1);
+
+    let name = symbol_name(vm, args[0], "unexport")?;
+    EXPORTED.with(|e| e.borrow_mut().remove(&name));
+    env::remove_var(&name);
+    Ok(Node::Empty)
+}
+
+// Parse a with-env binding, (NAME VALUE) sets NAME and (NAME) removes it.
+fn parse_binding(vm: &mut VM, binding: &Node) -> Result<Override, String> {
+    let l = match binding {
+        Node::List(l) => l,
+        _ => {
+            return Err(format!(
+                "with-env bindings must be lists, got {}",
+                binding.type_str()
+            ))
+        }
+    };
+
+    let mut items = l.iter();
+    let key = match items.next() {
+        Some(Node::Symbol(sym)) => sym.borrow().name().to_owned(),
+        Some(n) => format!("{}", n),
+        None => return Err("with-env binding is missing a name".to_owned()),
+    };
+
+    match items.next() {
+        Some(value) => Ok(Override::Set(key, format!("{}", vm.eval(value)?))),
+        None => Ok(Override::Remove(key)),
+    }
+}
+
+pub fn shell_with_env(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let args = args_setup!(args, "with-env", >=, 2);
+
+    // (with-env :clear BINDINGS FORM...) starts from an empty environment
+    let mut overrides = Vec::new();
+    let mut args = &args[..];
+    if is_keyword(args[0], ":clear") {
+        overrides.push(Override::Clear);
+        args = &args[1..];
+    }
+    if args.len() < 2 {
+        return Err("with-env expected bindings and at least one form".to_owned());
+    }
+
+    match args[0] {
+        Node::List(l) => {
+            for binding in l.iter() {
+                overrides.push(parse_binding(vm, binding)?);
+            }
+        }
+        Node::Empty => {}
+        n => {
+            return Err(format!(
+                "with-env bindings must be a list, got {}",
+                n.type_str()
+            ))
+        }
+    }
+
+    let depth = OVERRIDES.with(|o| {
+        let mut o = o.borrow_mut();
+        let depth = o.len();
+        o.extend(overrides);
+        depth
+    });
+
+    let mut ret = Ok(Node::Empty);
+    for form in &args[1..] {
+        ret = vm.eval(form);
+        if ret.is_err() {
+            break;
+        }
+    }
+
+    OVERRIDES.with(|o| o.borrow_mut().truncate(depth));
+    ret
+}
+
+// (env) lists the exported variables, printed when interactive or returned as
+// a map. With arguments the env command is run instead.
+pub fn shell_env(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    if args.head().is_some() {
+        return shell_call(vm, args.append(Node::from_string("env".to_owned())));
+    }
+
+    let interactive = is_interactive(vm);
+    let mut vars = HashMap::new();
+    let names = EXPORTED.with(|e| e.borrow().clone());
+    for name in names {
+        sync_var(vm, &name);
+        if let Some(value) = env::var_os(&name) {
+            let value = value.to_string_lossy().into_owned();
+            if interactive {
+                println!("{}={}", name, value);
+            } else {
+                vars.insert(name, Node::from_string(value));
+            }
+        }
+    }
+
+    if interactive {
+        Ok(Node::Empty)
+    } else {
+        Ok(Node::from_hashmap(vars))
+    }
+}
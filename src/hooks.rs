@@ -0,0 +1,66 @@
+use crate::{call_function, last_status, set_last_status};
+
+use lazuli_vm::args_setup;
+use lazuli_vm::object::cons_list::ConsList;
+use lazuli_vm::object::Node;
+use lazuli_vm::vm::VM;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// Events that hook functions can be added to, durations are in milliseconds:
+///
+/// - chpwd: called with the old and new directory after the working directory changes
+/// - precmd: called before the prompt with the last status and duration
+/// - preexec: called with the entered line before it's run
+/// - postexec: called with the line, its status, and its duration
+/// - exit: called once when the shell exits
+const HOOKS: [&str; 5] = ["chpwd", "precmd", "preexec", "postexec", "exit"];
+
+thread_local! {
+    static FUNCTIONS: RefCell<HashMap<&'static str, Vec<Node>>> = RefCell::new(HashMap::new());
+}
+
+/// Call every function added to a hook in the order they were added.
+pub fn run_hooks(vm: &mut VM, hook: &str, args: Vec<Node>) {
+    let funcs = FUNCTIONS.with(|f| f.borrow().get(hook).cloned().unwrap_or_default());
+    call_hooks(vm, hook, &funcs, args);
+}
+
+/// Run the exit hooks. They're removed first so they run once even if a
+/// hook calls exit.
+pub fn run_exit_hooks(vm: &mut VM) {
+    let funcs = FUNCTIONS.with(|f| f.borrow_mut().remove("exit").unwrap_or_default());
+    call_hooks(vm, "exit", &funcs, Vec::new());
+}
+
+// Errors are reported but don't stop the remaining hooks. Commands run by a
+// hook don't change last-status.
+fn call_hooks(vm: &mut VM, hook: &str, funcs: &[Node], args: Vec<Node>) {
+    if funcs.is_empty() {
+        return;
+    }
+
+    let status = last_status(vm);
+    for func in funcs {
+        if let Err(e) = call_function(vm, func, args.clone()) {
+            eprintln!("{} hook: {}", hook, e);
+        }
+    }
+    set_last_status(vm, status);
+}
+
+pub fn shell_add_hook(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let args = args_setup!(args, "add-hook", ==, 2);
+
+    let name = format!("{}", vm.eval(args[0])?);
+    let name = name.trim_start_matches(':');
+    let hook = match HOOKS.iter().find(|h| **h == name) {
+        Some(hook) => *hook,
+        None => return Err(format!("add-hook unknown hook {}", name)),
+    };
+    let func = vm.eval(args[1])?;
+    FUNCTIONS.with(|f| f.borrow_mut().entry(hook).or_default().push(func));
+
+    Ok(Node::Empty)
+}
@@ -0,0 +1,733 @@
+use crate::redirect::Streams;
+use crate::timing;
+use crate::{list_from_nodes, set_last_status};
+
+use lazuli_vm::args_setup;
+use lazuli_vm::object::cons_list::ConsList;
+use lazuli_vm::object::Node;
+use lazuli_vm::vm::VM;
+
+use libc::pid_t;
+
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{ChildStdout, Command, ExitStatus, Stdio};
+
+// Background jobs and jobs stopped with Ctrl-Z. Job ids start at 1.
+thread_local! {
+    static JOBS: RefCell<Vec<Job>> = const { RefCell::new(Vec::new()) };
+    static BACKGROUND: Cell<bool> = const { Cell::new(false) };
+    static JOB_CONTROL: Cell<bool> = const { Cell::new(false) };
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ProcState {
+    Running,
+    Stopped,
+    Exited(i32),
+    Signaled(i32),
+}
+
+impl ProcState {
+    fn is_done(self) -> bool {
+        matches!(self, ProcState::Exited(_) | ProcState::Signaled(_))
+    }
+
+    /// Exit status in the form stored in `last-status`. Processes killed or
+    /// stopped by a signal are reported as 128 plus the signal number.
+    pub fn code(self) -> i64 {
+        match self {
+            ProcState::Exited(c) => i64::from(c),
+            ProcState::Signaled(s) => 128 + i64::from(s),
+            ProcState::Stopped => 128 + i64::from(libc::SIGTSTP),
+            ProcState::Running => 0,
+        }
+    }
+
+    fn from_wait_status(status: libc::c_int) -> Option<Self> {
+        if libc::WIFEXITED(status) {
+            Some(ProcState::Exited(libc::WEXITSTATUS(status)))
+        } else if libc::WIFSIGNALED(status) {
+            Some(ProcState::Signaled(libc::WTERMSIG(status)))
+        } else if libc::WIFSTOPPED(status) {
+            Some(ProcState::Stopped)
+        } else if libc::WIFCONTINUED(status) {
+            Some(ProcState::Running)
+        } else {
+            None
+        }
+    }
+}
+
+pub struct Process {
+    pub pid: pid_t,
+    pub state: ProcState,
+}
+
+pub struct Job {
+    pub id: usize,
+    // Set when the job runs in its own process group
+    pub pgid: Option<pid_t>,
+    pub procs: Vec<Process>,
+    pub command: String,
+}
+
+impl Job {
+    pub fn is_done(&self) -> bool {
+        self.procs.iter().all(|p| p.state.is_done())
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        !self.is_done()
+            && self
+                .procs
+                .iter()
+                .all(|p| p.state == ProcState::Stopped || p.state.is_done())
+    }
+
+    /// The state of the job is the state of the last process in the pipeline.
+    pub fn last_state(&self) -> ProcState {
+        self.procs
+            .last()
+            .map(|p| p.state)
+            .unwrap_or(ProcState::Exited(0))
+    }
+
+    fn state_str(&self) -> String {
+        if self.is_done() {
+            match self.last_state() {
+                ProcState::Exited(0) => "Done".to_owned(),
+                ProcState::Exited(c) => format!("Exit {}", c),
+                ProcState::Signaled(s) => format!("Killed ({})", s),
+                _ => "Done".to_owned(),
+            }
+        } else if self.is_stopped() {
+            "Stopped".to_owned()
+        } else {
+            "Running".to_owned()
+        }
+    }
+
+    fn signal(&self, sig: libc::c_int) -> io::Result<()> {
+        let res = match self.pgid {
+            Some(pgid) => unsafe { libc::killpg(pgid, sig) },
+            None => {
+                let mut res = 0;
+                for p in self.procs.iter().filter(|p| !p.state.is_done()) {
+                    if unsafe { libc::kill(p.pid, sig) } < 0 {
+                        res = -1;
+                    }
+                }
+                res
+            }
+        };
+
+        if res < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn continue_job(&mut self) -> io::Result<()> {
+        self.signal(libc::SIGCONT)?;
+        for p in self.procs.iter_mut().filter(|p| !p.state.is_done()) {
+            p.state = ProcState::Running;
+        }
+        Ok(())
+    }
+
+    /// Block until every process has finished or the job is stopped.
+    fn wait(&mut self) {
+        for p in self.procs.iter_mut() {
+            while p.state == ProcState::Running {
+                let mut status = 0;
+                let res = wait_pid(p.pid, &mut status, libc::WUNTRACED);
+
+                if res < 0 {
+                    if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    // The child was reaped elsewhere, nothing left to wait for
+                    p.state = ProcState::Exited(0);
+                } else if let Some(state) = ProcState::from_wait_status(status) {
+                    p.state = state;
+                }
+            }
+
+            if p.state == ProcState::Stopped {
+                return;
+            }
+        }
+    }
+
+    /// Check for state changes without blocking.
+    fn poll(&mut self) {
+        for p in self.procs.iter_mut().filter(|p| !p.state.is_done()) {
+            let mut status = 0;
+            let res = wait_pid(
+                p.pid,
+                &mut status,
+                libc::WNOHANG | libc::WUNTRACED | libc::WCONTINUED,
+            );
+
+            if res < 0 {
+                p.state = ProcState::Exited(0);
+            } else if res > 0 {
+                if let Some(state) = ProcState::from_wait_status(status) {
+                    p.state = state;
+                }
+            }
+        }
+    }
+
+    fn print_status(&self) {
+        println!("[{}]  {:<20}{}", self.id, self.state_str(), self.command);
+    }
+}
+
+fn next_job_id() -> usize {
+    JOBS.with(|j| j.borrow().iter().map(|j| j.id).max().unwrap_or(0) + 1)
+}
+
+fn add_job(job: Job) {
+    JOBS.with(|j| {
+        let mut jobs = j.borrow_mut();
+        jobs.push(job);
+        jobs.sort_by_key(|j| j.id);
+    });
+}
+
+fn take_job(id: Option<usize>) -> Result<Job, String> {
+    JOBS.with(|j| {
+        let mut jobs = j.borrow_mut();
+        // Default to the most recent job
+        let idx = match id {
+            Some(id) => jobs.iter().position(|j| j.id == id),
+            None => jobs.iter().rposition(|j| j.is_stopped()).or_else(|| {
+                if jobs.is_empty() {
+                    None
+                } else {
+                    Some(jobs.len() - 1)
+                }
+            }),
+        };
+
+        match idx {
+            Some(idx) => Ok(jobs.remove(idx)),
+            None => match id {
+                Some(id) => Err(format!("no such job {}", id)),
+                None => Err("no current job".to_owned()),
+            },
+        }
+    })
+}
+
+// waitpid that also adds the resource usage of a child that has exited to the
+// totals reported by time.
+fn wait_pid(pid: pid_t, status: &mut i32, options: i32) -> pid_t {
+    let mut usage = MaybeUninit::<libc::rusage>::zeroed();
+    let res = unsafe { libc::wait4(pid, status, options, usage.as_mut_ptr()) };
+    if res > 0 && (libc::WIFEXITED(*status) || libc::WIFSIGNALED(*status)) {
+        timing::add_child_usage(unsafe { &usage.assume_init() });
+    }
+    res
+}
+
+/// Wait for a child started outside of job control to exit.
+pub fn wait_child(pid: u32) -> io::Result<ExitStatus> {
+    loop {
+        let mut status = 0;
+        if wait_pid(pid as pid_t, &mut status, 0) >= 0 {
+            return Ok(ExitStatus::from_raw(status));
+        }
+
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+/// Convert the exit status of a captured command to the form stored in `last-status`.
+pub fn status_code(status: ExitStatus) -> i64 {
+    match (status.code(), status.signal()) {
+        (Some(c), _) => i64::from(c),
+        (None, Some(s)) => 128 + i64::from(s),
+        (None, None) => 255,
+    }
+}
+
+/// Setup the shell process for job control. The shell is put in its own process
+/// group and takes the terminal, foreground jobs are given the terminal while they run.
+/// Stopping signals are ignored so Ctrl-Z only reaches the foreground job.
+pub fn init_interactive() {
+    unsafe {
+        libc::signal(libc::SIGQUIT, libc::SIG_IGN);
+        libc::signal(libc::SIGTSTP, libc::SIG_IGN);
+        libc::signal(libc::SIGTTIN, libc::SIG_IGN);
+        libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+    }
+
+    if has_terminal() {
+        unsafe {
+            // Fails if the shell is already a session leader, which is fine
+            libc::setpgid(0, 0);
+        }
+        reclaim_terminal();
+        JOB_CONTROL.with(|j| j.set(true));
+    }
+}
+
+fn job_control() -> bool {
+    JOB_CONTROL.with(|j| j.get())
+}
+
+/// Ignored signals are inherited across exec, children need the defaults back.
+pub fn reset_child_signals(cmd: &mut Command) {
+    unsafe {
+        cmd.pre_exec(|| {
+            for sig in &CHILD_SIGNALS {
+                libc::signal(*sig, libc::SIG_DFL);
+            }
+            Ok(())
+        });
+    }
+}
+
+const CHILD_SIGNALS: [libc::c_int; 5] = [
+    libc::SIGINT,
+    libc::SIGQUIT,
+    libc::SIGTSTP,
+    libc::SIGTTIN,
+    libc::SIGTTOU,
+];
+
+/// Ignores terminal signals in the shell while a child is running so they
+/// only affect the child. The previous handlers are restored on drop.
+pub struct SignalGuard {
+    prev: Vec<(libc::c_int, libc::sighandler_t)>,
+}
+
+impl SignalGuard {
+    pub fn new() -> Self {
+        let prev = [libc::SIGINT, libc::SIGQUIT, libc::SIGTSTP]
+            .iter()
+            .map(|sig| (*sig, unsafe { libc::signal(*sig, libc::SIG_IGN) }))
+            .collect();
+        SignalGuard { prev }
+    }
+}
+
+impl Drop for SignalGuard {
+    fn drop(&mut self) {
+        for (sig, handler) in self.prev.iter() {
+            unsafe {
+                libc::signal(*sig, *handler);
+            }
+        }
+    }
+}
+
+fn has_terminal() -> bool {
+    unsafe { libc::isatty(libc::STDIN_FILENO) == 1 }
+}
+
+fn give_terminal(pgid: pid_t) {
+    if has_terminal() {
+        unsafe {
+            libc::tcsetpgrp(libc::STDIN_FILENO, pgid);
+        }
+    }
+}
+
+fn reclaim_terminal() {
+    give_terminal(unsafe { libc::getpgrp() });
+}
+
+/// Returns true if commands should be started as background jobs.
+pub fn is_background() -> bool {
+    BACKGROUND.with(|b| b.get())
+}
+
+/// Start a pipeline of commands, a single command is a pipeline of one. Foreground
+/// jobs are waited on and the final state of each stage returned, background jobs
+/// return immediately.
+pub fn launch(
+    stages: Vec<Command>,
+    streams: Streams,
+    command: String,
+) -> io::Result<Vec<ProcState>> {
+    let background = is_background();
+    // With job control every job gets its own process group, otherwise only
+    // background jobs do so terminal signals don't reach them.
+    let own_group = background || job_control();
+    let Streams {
+        stdin,
+        stdout,
+        stderr,
+        stderr_to_stdout,
+    } = streams;
+
+    let mut stdin = stdin;
+    let mut prev_stdout: Option<ChildStdout> = None;
+    let mut pgid: Option<pid_t> = None;
+    let mut procs = Vec::with_capacity(stages.len());
+    let mut spawn_err = None;
+    let last = stages.len().saturating_sub(1);
+
+    for (i, mut cmd) in stages.into_iter().enumerate() {
+        if let Some(out) = prev_stdout.take() {
+            cmd.stdin(out);
+        } else if let Some(f) = stdin.take() {
+            cmd.stdin(f);
+        }
+
+        if i < last {
+            cmd.stdout(Stdio::piped());
+        } else if let Some(f) = &stdout {
+            cmd.stdout(f.try_clone()?);
+        }
+
+        if let Some(f) = &stderr {
+            cmd.stderr(f.try_clone()?);
+        } else if stderr_to_stdout {
+            cmd.stderr(io::stdout());
+        }
+
+        if own_group {
+            cmd.process_group(pgid.unwrap_or(0));
+        }
+        if own_group && !background {
+            // Take the terminal in the child as well so it can't read before the shell hands it over
+            unsafe {
+                cmd.pre_exec(|| {
+                    libc::tcsetpgrp(libc::STDIN_FILENO, libc::getpgrp());
+                    Ok(())
+                });
+            }
+        }
+        reset_child_signals(&mut cmd);
+
+        match cmd.spawn() {
+            Ok(mut child) => {
+                let pid = child.id() as pid_t;
+                if own_group && pgid.is_none() {
+                    pgid = Some(pid);
+                }
+                prev_stdout = child.stdout.take();
+                procs.push(Process {
+                    pid,
+                    state: ProcState::Running,
+                });
+            }
+            Err(e) => {
+                spawn_err = Some(e);
+                break;
+            }
+        }
+    }
+    drop(prev_stdout);
+
+    let mut job = Job {
+        id: 0,
+        pgid,
+        procs,
+        command,
+    };
+
+    if let Some(e) = spawn_err {
+        // Reap anything that did start, it will exit once its pipe closes
+        job.wait();
+        return Err(e);
+    }
+
+    if background {
+        job.id = next_job_id();
+        println!(
+            "[{}] {}",
+            job.id,
+            job.procs.last().map(|p| p.pid).unwrap_or(0)
+        );
+        let states = vec![ProcState::Exited(0); job.procs.len()];
+        add_job(job);
+        return Ok(states);
+    }
+
+    {
+        let _guard = SignalGuard::new();
+        if let Some(pgid) = job.pgid {
+            give_terminal(pgid);
+        }
+        job.wait();
+        if job.pgid.is_some() {
+            reclaim_terminal();
+        }
+    }
+    let stopped = job.is_stopped();
+    // Stages that haven't finished are reported as stopped with the job
+    let states = job
+        .procs
+        .iter()
+        .map(|p| match p.state {
+            ProcState::Running if stopped => ProcState::Stopped,
+            state => state,
+        })
+        .collect();
+
+    if stopped {
+        job.id = next_job_id();
+        println!();
+        job.print_status();
+        add_job(job);
+    }
+
+    Ok(states)
+}
+
+/// Print and remove jobs that finished since the last call.
+pub fn notify_finished() {
+    JOBS.with(|j| {
+        let mut jobs = j.borrow_mut();
+        for job in jobs.iter_mut() {
+            job.poll();
+        }
+
+        jobs.retain(|job| {
+            if job.is_done() {
+                job.print_status();
+                false
+            } else {
+                true
+            }
+        });
+    });
+}
+
+fn parse_job_id(n: &Node) -> Result<usize, String> {
+    let s = format!("{}", n);
+    s.trim_start_matches('%')
+        .parse()
+        .map_err(|_| format!("invalid job id {}", s))
+}
+
+fn eval_job_id(vm: &mut VM, args: &[&Node]) -> Result<Option<usize>, String> {
+    match args.first() {
+        Some(n) => Ok(Some(parse_job_id(&vm.eval(n)?)?)),
+        None => Ok(None),
+    }
+}
+
+fn run_foreground(vm: &mut VM, mut job: Job) -> Result<Node, String> {
+    println!("{}", job.command);
+
+    let _guard = SignalGuard::new();
+    if let Some(pgid) = job.pgid {
+        give_terminal(pgid);
+    }
+    let res = job.continue_job();
+    if res.is_ok() {
+        job.wait();
+    }
+    if job.pgid.is_some() {
+        reclaim_terminal();
+    }
+
+    if let Err(e) = res {
+        add_job(job);
+        return Err(format!("fg: {}", e));
+    }
+
+    if job.is_stopped() {
+        println!();
+        job.print_status();
+        add_job(job);
+    } else {
+        set_last_status(vm, job.last_state().code());
+    }
+    Ok(Node::Empty)
+}
+
+pub fn shell_background(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let args = args_setup!(args, "&", >=, 1);
+
+    let prev = BACKGROUND.with(|b| b.replace(true));
+    let ret = match args[0] {
+        Node::List(_) => {
+            let mut ret = Ok(Node::Empty);
+            for form in args.iter() {
+                ret = vm.eval(form);
+                if ret.is_err() {
+                    break;
+                }
+            }
+            ret
+        }
+        // A bare command, (& sleep 10)
+        _ => crate::shell_call(vm, list_from_nodes(&args)),
+    };
+    BACKGROUND.with(|b| b.set(prev));
+
+    ret
+}
+
+pub fn shell_jobs(_vm: &mut VM, _args: ConsList<Node>) -> Result<Node, String> {
+    // Finished jobs are reported once, here instead of before the next prompt
+    JOBS.with(|j| {
+        let mut jobs = j.borrow_mut();
+        for job in jobs.iter_mut() {
+            job.poll();
+            job.print_status();
+        }
+        jobs.retain(|job| !job.is_done());
+    });
+    Ok(Node::Empty)
+}
+
+pub fn shell_fg(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let args = args_setup!(args);
+    let id = eval_job_id(vm, &args)?;
+    let job = take_job(id)?;
+    run_foreground(vm, job)
+}
+
+pub fn shell_bg(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let args = args_setup!(args);
+
+    // (bg FORM...) is an alias for (& FORM...)
+    if let Some(Node::List(_)) = args.first() {
+        return shell_background(vm, list_from_nodes(&args));
+    }
+
+    let id = eval_job_id(vm, &args)?;
+    let mut job = take_job(id)?;
+    let res = job.continue_job();
+    if res.is_ok() {
+        println!("[{}] {} &", job.id, job.command);
+    }
+    add_job(job);
+
+    res.map(|_| Node::Empty).map_err(|e| format!("bg: {}", e))
+}
+
+pub fn shell_wait(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let args = args_setup!(args);
+
+    let mut ids = Vec::with_capacity(args.len());
+    for arg in args.iter() {
+        ids.push(parse_job_id(&vm.eval(arg)?)?);
+    }
+    if ids.is_empty() {
+        ids = JOBS.with(|j| {
+            j.borrow()
+                .iter()
+                .filter(|j| !j.is_stopped())
+                .map(|j| j.id)
+                .collect()
+        });
+    }
+
+    let _guard = SignalGuard::new();
+    for id in ids {
+        let mut job = take_job(Some(id))?;
+        job.wait();
+        let state = job.last_state();
+
+        if job.is_done() {
+            set_last_status(vm, state.code());
+        } else {
+            add_job(job);
+        }
+    }
+
+    Ok(Node::Empty)
+}
+
+fn parse_signal(s: &str) -> Option<libc::c_int> {
+    if let Ok(n) = s.parse() {
+        return Some(n);
+    }
+
+    let name = s.to_uppercase();
+    let name = name.trim_start_matches("SIG");
+    let sig = match name {
+        "HUP" => libc::SIGHUP,
+        "INT" => libc::SIGINT,
+        "QUIT" => libc::SIGQUIT,
+        "KILL" => libc::SIGKILL,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        "TERM" => libc::SIGTERM,
+        "CONT" => libc::SIGCONT,
+        "STOP" => libc::SIGSTOP,
+        "TSTP" => libc::SIGTSTP,
+        _ => return None,
+    };
+    Some(sig)
+}
+
+// A stopped job is continued after the signal so it can act on it, unless the
+// signal would only stop it again.
+fn signal_job(job: &mut Job, sig: libc::c_int) -> io::Result<()> {
+    job.signal(sig)?;
+
+    let no_continue = [
+        libc::SIGCONT,
+        libc::SIGSTOP,
+        libc::SIGTSTP,
+        libc::SIGTTIN,
+        libc::SIGTTOU,
+    ];
+    if job.is_stopped() && !no_continue.contains(&sig) {
+        job.continue_job()?;
+    }
+    Ok(())
+}
+
+pub fn shell_kill(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let args = args_setup!(args, "kill", >=, 1);
+
+    let mut targets = Vec::with_capacity(args.len());
+    for arg in args.iter() {
+        targets.push(format!("{}", vm.eval(arg)?));
+    }
+
+    // (kill -9 %1) or (kill :term 1234)
+    let mut sig = libc::SIGTERM;
+    if targets.len() > 1 && (targets[0].starts_with('-') || targets[0].starts_with(':')) {
+        sig = parse_signal(&targets[0][1..])
+            .ok_or_else(|| format!("kill: invalid signal {}", targets[0]))?;
+        targets.remove(0);
+    }
+
+    for target in targets {
+        let res = if target.starts_with('%') {
+            let id = parse_job_id(&Node::from_string(target.clone()))?;
+            JOBS.with(|j| match j.borrow_mut().iter_mut().find(|j| j.id == id) {
+                Some(job) => signal_job(job, sig).map_err(|e| format!("{}", e)),
+                None => Err(format!("no such job {}", id)),
+            })
+        } else {
+            match target.parse::<pid_t>() {
+                Ok(pid) => {
+                    if unsafe { libc::kill(pid, sig) } < 0 {
+                        Err(format!("{}", io::Error::last_os_error()))
+                    } else {
+                        Ok(())
+                    }
+                }
+                Err(_) => Err(format!("invalid process id {}", target)),
+            }
+        };
+
+        if let Err(e) = res {
+            set_last_status(vm, 1);
+            return Err(format!("kill: {}", e));
+        }
+    }
+
+    set_last_status(vm, 0);
+    Ok(Node::Empty)
+}
@@ -1,8 +1,23 @@
+mod commands;
+mod completion;
+mod directory;
+mod environment;
+mod hooks;
+mod jobs;
+mod options;
+mod pipeline;
+mod reader;
+mod redirect;
 mod terminal;
+mod timing;
 
 use clap::Parser as ClapParser;
+use completion::ShellCompleter;
 use dirs::config_dir;
+use environment::EnvChanges;
 use path_absolutize::*;
+use pipeline::Stage;
+use redirect::Streams;
 use shellexpand::tilde;
 use terminal::Terminal;
 
@@ -17,12 +32,15 @@ use lazuli_vm::vm::VM;
 use std::collections::HashMap;
 use std::env;
 use std::ffi::OsString;
-use std::io::ErrorKind;
-use std::path;
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::os::fd::AsFd;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::time::Instant;
 
 const DEFAULT_PROMPT: &str = "lish$ ";
+const DEFAULT_PROMPT2: &str = "> ";
 
 #[derive(clap::Parser)]
 #[command(author, version, about)]
@@ -30,20 +48,52 @@ struct Cli {
     #[arg(short, long, value_name = "FILE")]
     startup_file: Option<PathBuf>,
 
+    #[arg(short, long)]
+    login: bool,
+
+    #[arg(short = 'c', value_name = "COMMAND")]
+    command: Option<String>,
+
     #[arg(value_name = "SCRIPT")]
     script: Option<PathBuf>,
+
+    #[arg(
+        value_name = "ARGS",
+        trailing_var_arg = true,
+        allow_hyphen_values = true
+    )]
+    args: Vec<String>,
 }
 
 fn main() {
     let app = Cli::parse();
 
-    match app.script {
-        Some(f) => compile_file(&f),
-        None => interactive_shell(app.startup_file.as_ref()),
+    // Login programs start the shell with a dash before its name
+    let login = app.login
+        || env::args_os()
+            .next()
+            .is_some_and(|arg0| arg0.to_string_lossy().starts_with('-'));
+    init_env();
+
+    match (app.command, app.script) {
+        (Some(command), script) => {
+            // Without a script every positional argument belongs to the command
+            let args: Vec<String> = script
+                .map(|p| p.to_string_lossy().into_owned())
+                .into_iter()
+                .chain(app.args)
+                .collect();
+            run_command(&command, &args)
+        }
+        (None, Some(f)) => compile_file(&f, &app.args),
+        (None, None) if termion::is_tty(&io::stdin()) && termion::is_tty(&io::stdout()) => {
+            interactive_shell(app.startup_file.as_ref(), login)
+        }
+        (None, None) => stdin_shell(),
     }
 }
 
-fn compile_file(path: &PathBuf) {
+fn compile_file(path: &PathBuf, args: &[String]) {
     let src_path = Path::new(path);
     let code = compiler::compile_file(src_path).unwrap_or_else(|e| {
         eprintln!("{}", e);
@@ -57,56 +107,155 @@ fn compile_file(path: &PathBuf) {
         .to_owned();
 
     let mut vm = setup_vm(false);
+    set_args(&mut vm, &path.to_string_lossy(), args);
     vm.add_symbol(
         Symbol::with_value("curr-script-path", Node::from_string(abs_file_path.clone())).into_ref(),
     );
     vm.add_filename(&abs_file_path);
-    if let Err(e) = vm.run(&code) {
-        eprintln!("Error: {}", e);
-    }
+    let status = match vm.run(&code) {
+        Ok(_) if options::is_set("exit-last-status") => last_status(&mut vm),
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            options::take_failed_status().unwrap_or(1)
+        }
+    };
+    exit_shell(&mut vm, status);
 }
 
 fn setup_vm(interactive: bool) -> VM {
     let mut vm = VM::new();
     // Builtin Functions
-    vm.add_symbol(Symbol::with_builtin("exit", shell_exit).into_ref());
-    vm.add_symbol(Symbol::with_builtin("pwd", shell_pwd).into_ref());
-    vm.add_symbol(Symbol::with_builtin("cd", shell_cd).into_ref());
-    vm.add_symbol(Symbol::with_builtin("capc", shell_captured_call).into_ref());
-    vm.add_symbol(Symbol::with_builtin("call", shell_call).into_ref());
-    vm.add_symbol(Symbol::with_builtin("pipe", shell_pipe).into_ref());
-    vm.add_symbol(Symbol::with_builtin("|", shell_pipe).into_ref());
-    vm.add_symbol(Symbol::with_builtin("export", shell_export).into_ref());
-    vm.add_symbol(Symbol::with_builtin("unexport", shell_unexport).into_ref());
-    vm.add_symbol(Symbol::with_builtin("prompt", shell_default_prompt).into_ref());
-    vm.add_symbol(Symbol::with_builtin("glob", shell_glob).into_ref());
+    add_builtin(&mut vm, "exit", shell_exit);
+    add_builtin(&mut vm, "pwd", shell_pwd);
+    add_builtin(&mut vm, "cd", directory::shell_cd);
+    add_builtin(&mut vm, "capc", shell_captured_call);
+    add_builtin(&mut vm, "call", shell_call);
+    add_builtin(&mut vm, "pipe", shell_pipe);
+    add_builtin(&mut vm, "|", shell_pipe);
+    add_builtin(&mut vm, "export", environment::shell_export);
+    add_builtin(&mut vm, "unexport", environment::shell_unexport);
+    add_builtin(&mut vm, "env", environment::shell_env);
+    add_builtin(&mut vm, "with-env", environment::shell_with_env);
+    add_builtin(&mut vm, "prompt", shell_default_prompt);
+    add_builtin(&mut vm, "prompt2", shell_default_prompt2);
+    add_builtin(&mut vm, "glob", shell_glob);
+
+    // Directory stack
+    add_builtin(&mut vm, "pushd", directory::shell_pushd);
+    add_builtin(&mut vm, "popd", directory::shell_popd);
+    add_builtin(&mut vm, "dirs", directory::shell_dirs);
+
+    // Redirection, stdout and stdin are numbered so < and > stay comparisons
+    add_builtin(&mut vm, "1>", redirect::shell_redirect_stdout);
+    add_builtin(&mut vm, "1>>", redirect::shell_redirect_stdout_append);
+    add_builtin(&mut vm, "0<", redirect::shell_redirect_stdin);
+    add_builtin(&mut vm, "2>", redirect::shell_redirect_stderr);
+    add_builtin(&mut vm, "2>>", redirect::shell_redirect_stderr_append);
+    add_builtin(&mut vm, "2>&1", redirect::shell_redirect_stderr_to_stdout);
+    add_builtin(&mut vm, "&>", redirect::shell_redirect_all);
+    add_builtin(&mut vm, "&>>", redirect::shell_redirect_all_append);
+
+    // Job control
+    add_builtin(&mut vm, "&", jobs::shell_background);
+    add_builtin(&mut vm, "jobs", jobs::shell_jobs);
+    add_builtin(&mut vm, "fg", jobs::shell_fg);
+    add_builtin(&mut vm, "bg", jobs::shell_bg);
+    add_builtin(&mut vm, "wait", jobs::shell_wait);
+    add_builtin(&mut vm, "kill", jobs::shell_kill);
+
+    // Command lookup
+    add_builtin(&mut vm, "rehash", commands::shell_rehash);
+    add_builtin(&mut vm, "which", commands::shell_which);
+    add_builtin(&mut vm, "type", commands::shell_type);
+
+    // Completion
+    add_builtin(&mut vm, "defcompletion", completion::shell_defcompletion);
+
+    // Timing
+    add_builtin(&mut vm, "time", timing::shell_time);
+
+    // Hooks
+    add_builtin(&mut vm, "add-hook", hooks::shell_add_hook);
+
+    // Shell options
+    add_builtin(&mut vm, "set-option", options::shell_set_option);
+
+    // Line editor
+    add_builtin(&mut vm, "bind-key", shell_bind_key);
+    add_builtin(&mut vm, "set-edit-mode", shell_set_edit_mode);
 
     // Predefined variables
     vm.add_symbol(Symbol::with_value("interactive", Node::bool_obj(interactive)).into_ref());
     vm.add_symbol(Symbol::with_value("last-status", Node::Number(0)).into_ref());
+    vm.add_symbol(Symbol::with_value("last-duration", Node::Number(0)).into_ref());
+    vm.add_symbol(Symbol::with_value("pipestatus", node_list(vec![Node::Number(0)])).into_ref());
+    vm.add_symbol(Symbol::with_value("login-shell", Node::bool_obj(false)).into_ref());
+    set_args(&mut vm, &env::args().next().unwrap_or_default(), &[]);
 
-    for (key, value) in env::vars() {
-        vm.add_symbol(Symbol::with_value(&key, Node::from_string(value)).into_ref());
-    }
+    environment::init(&mut vm);
 
     // Override VM define so we can disable interactive shell during exec
     let vm_define = vm.symbols.borrow().get_symbol("define");
     let mut shadow_define = Symbol::new("_define");
     shadow_define.function = vm_define.borrow().function.clone();
     vm.add_symbol(shadow_define.into_ref());
-    vm.add_symbol(Symbol::with_builtin("define", shell_define).into_ref());
+    add_builtin(&mut vm, "define", shell_define);
+
+    // Record names given to setf and define-syntax for completion
+    shadow_builtin(&mut vm, "setf", shell_setf);
+    shadow_builtin(&mut vm, "define-syntax", shell_define_syntax);
 
     vm.set_cmd_not_found(Callable::Builtin(shell_call));
     vm
 }
 
-fn get_default_rc_filepath() -> Option<PathBuf> {
-    match config_dir() {
-        Some(p) => Some(p.join("lish").join("init.lisp")),
-        None => None,
+type Builtin = fn(&mut VM, ConsList<Node>) -> Result<Node, String>;
+
+fn add_builtin(vm: &mut VM, name: &str, f: Builtin) {
+    vm.add_symbol(Symbol::with_builtin(name, f).into_ref());
+    commands::add_name(name);
+}
+
+// Replace a VM builtin, the original is available with an underscore prefix.
+// Nothing is changed if the symbol isn't a builtin.
+fn shadow_builtin(vm: &mut VM, name: &str, f: Builtin) {
+    let vm_func = vm.symbols.borrow().get_symbol(name);
+    let func = vm_func.borrow().function.clone();
+    if let Callable::Builtin(_) = func {
+        let mut shadow = Symbol::new(&format!("_{}", name));
+        shadow.function = func;
+        vm.add_symbol(shadow.into_ref());
+        vm.add_symbol(Symbol::with_builtin(name, f).into_ref());
     }
 }
 
+/// Call a Lisp function value with already evaluated arguments.
+fn call_function(vm: &mut VM, func: &Node, args: Vec<Node>) -> Result<Node, String> {
+    let name = match func {
+        Node::Symbol(sym) => sym.borrow().name().to_owned(),
+        _ => {
+            vm.add_symbol(Symbol::with_value("__lish-callback", func.clone()).into_ref());
+            "__lish-callback".to_owned()
+        }
+    };
+
+    let call = args
+        .into_iter()
+        .rev()
+        .fold(ConsList::new(), |list, n| list.append(n))
+        .append(Symbol::new(&name).into_node());
+    vm.eval_list(&call)
+}
+
+fn get_default_rc_filepath() -> Option<PathBuf> {
+    get_config_filepath("init.lisp")
+}
+
+fn get_config_filepath(name: &str) -> Option<PathBuf> {
+    config_dir().map(|p| p.join("lish").join(name))
+}
+
 fn get_default_history_filepath() -> PathBuf {
     match config_dir() {
         Some(p) => p.join("lish").join(".history"),
@@ -114,7 +263,7 @@ fn get_default_history_filepath() -> PathBuf {
     }
 }
 
-fn interactive_shell(startup_file: Option<&PathBuf>) {
+fn interactive_shell(startup_file: Option<&PathBuf>, login: bool) {
     let mut term = Terminal::new(get_default_history_filepath());
     if let Err(e) = term.load_history() {
         match e.kind() {
@@ -125,8 +274,17 @@ fn interactive_shell(startup_file: Option<&PathBuf>) {
         }
     }
 
+    jobs::init_interactive();
     let mut vm = setup_vm(true);
 
+    // Login shells source the profile before the startup file
+    if login {
+        vm.add_symbol(Symbol::with_value("login-shell", Node::bool_obj(true)).into_ref());
+        if let Some(filename) = get_config_filepath("profile.lisp") {
+            source_file(&mut vm, &filename);
+        }
+    }
+
     let rc_path = match startup_file {
         Some(filename) => Some(Path::new(filename).to_path_buf()),
         None => get_default_rc_filepath(),
@@ -134,67 +292,273 @@ fn interactive_shell(startup_file: Option<&PathBuf>) {
 
     // Source startup file if one is given
     if let Some(filename) = rc_path {
-        if filename.exists() {
-            match compiler::compile_file(&filename) {
-                Ok(code) => {
-                    vm.add_filename(filename.absolutize().unwrap_or_default());
-                    if let Err(e) = vm.run(&code) {
-                        eprintln!("Error: {}", e);
-                    }
-                    vm.pop_filename();
-                }
-                Err(e) => eprintln!("{}", e),
-            };
-        }
+        source_file(&mut vm, &filename);
     }
 
-    let prompt_func =
-        ConsList::new().append(Symbol::with_value("prompt", Node::empty_list()).into_node());
+    // Milliseconds the last command took
+    let mut duration = 0;
 
     loop {
-        let mut line = match vm.eval_list(&prompt_func) {
-            Ok(node) => match node {
-                Node::String(s) => term.readline(&s),
+        jobs::notify_finished();
+
+        let status = last_status(&mut vm);
+        let args = vec![Node::Number(status), Node::Number(mem::take(&mut duration))];
+        hooks::run_hooks(&mut vm, "precmd", args);
+
+        let line = match read_form(&mut term, &mut vm) {
+            Ok(line) => line,
+            Err(e) => match e.kind() {
+                ErrorKind::Interrupted => continue,
+                ErrorKind::UnexpectedEof => break,
                 _ => {
-                    println!(
-                        "prompt didn't return a String, returned {}",
-                        node.type_str()
-                    );
-                    term.readline(&DEFAULT_PROMPT)
+                    eprintln!("{}", e);
+                    break;
                 }
             },
-            _ => term.readline(&DEFAULT_PROMPT),
         };
 
-        if !line.starts_with('(') {
-            line = format!("({})", line);
+        let text = Node::from_string(reader::flatten(&line));
+        hooks::run_hooks(&mut vm, "preexec", vec![text.clone()]);
+
+        let start = Instant::now();
+        if let Err(e) = run_line(&mut vm, &line) {
+            eprintln!("{}", e);
+        }
+        duration = start.elapsed().as_millis() as i64;
+        vm.add_symbol(Symbol::with_value("last-duration", Node::Number(duration)).into_ref());
+
+        let status = last_status(&mut vm);
+        let args = vec![text, Node::Number(status), Node::Number(duration)];
+        hooks::run_hooks(&mut vm, "postexec", args);
+    }
+
+    exit_shell(&mut vm, 0);
+}
+
+/// Run the exit hooks and logout.lisp, then exit.
+fn exit_shell(vm: &mut VM, status: i64) -> ! {
+    hooks::run_exit_hooks(vm);
+    run_logout(vm);
+    ::std::process::exit(status as i32);
+}
+
+fn source_file(vm: &mut VM, filename: &Path) {
+    if !filename.exists() {
+        return;
+    }
+
+    match compiler::compile_file(filename) {
+        Ok(code) => {
+            vm.add_filename(filename.absolutize().unwrap_or_default());
+            if let Err(e) = vm.run(&code) {
+                eprintln!("Error: {}", e);
+            }
+            vm.pop_filename();
+        }
+        Err(e) => eprintln!("{}", e),
+    };
+}
+
+/// Source logout.lisp when a login shell exits.
+fn run_logout(vm: &mut VM) {
+    let login = vm.symbols.borrow().get_symbol("login-shell");
+    if !login.borrow().value().is_truthy() {
+        return;
+    }
+
+    // Only run once, logout.lisp may call exit itself
+    vm.add_symbol(Symbol::with_value("login-shell", Node::bool_obj(false)).into_ref());
+    if let Some(filename) = get_config_filepath("logout.lisp") {
+        source_file(vm, &filename);
+    }
+}
+
+/// Set the environment variables a shell is expected to maintain.
+fn init_env() {
+    let level = env::var("SHLVL")
+        .ok()
+        .and_then(|l| l.parse::<i64>().ok())
+        .unwrap_or(0);
+    env::set_var("SHLVL", (level.max(0) + 1).to_string());
+
+    // Keep an inherited PWD if it names the current directory, it may go through a symlink
+    if let Ok(cwd) = env::current_dir() {
+        let inherited = env::var_os("PWD")
+            .and_then(|pwd| Path::new(&pwd).canonicalize().ok())
+            .is_some_and(|pwd| Some(pwd) == cwd.canonicalize().ok());
+        if !inherited {
+            env::set_var("PWD", &cwd);
         }
-        let mut line_iter = line.bytes();
-        let mut str_iter = ByteIter::new(&mut line_iter);
-        let mut lex = Lexer::new(&mut str_iter, "<shell>");
-        let parser = Parser::new(&mut lex);
-        let tree = match parser.parse() {
-            Ok(t) => t,
+    }
+
+    if env::var_os("SHELL").is_none() {
+        if let Ok(exe) = env::current_exe() {
+            env::set_var("SHELL", exe);
+        }
+    }
+}
+
+/// Run a command string given with -c and exit with its status. Arguments
+/// after the string are available in `argv`, an error exits with status 1.
+fn run_command(command: &str, args: &[String]) {
+    let mut vm = setup_vm(true);
+    set_args(&mut vm, "-c", args);
+
+    let status = match run_line(&mut vm, command) {
+        Ok(_) => last_status(&mut vm),
+        Err(e) => {
+            eprintln!("{}", e);
+            options::take_failed_status().unwrap_or(1)
+        }
+    };
+    exit_shell(&mut vm, status);
+}
+
+/// Run s-expressions read from stdin when it isn't a terminal, such as
+/// `echo '(pwd)' | lish`. Errors set the status to 1, the shell exits with the
+/// last status at the end of input.
+fn stdin_shell() {
+    let mut vm = setup_vm(true);
+    let mut form = String::new();
+
+    // Read without buffering so commands that read stdin start right after
+    // the form that ran them.
+    let mut stdin = match io::stdin().as_fd().try_clone_to_owned() {
+        Ok(fd) => File::from(fd),
+        Err(e) => {
+            eprintln!("{}", e);
+            exit_shell(&mut vm, 1);
+        }
+    };
+
+    loop {
+        let line = match reader::read_line(&mut stdin) {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
             Err(e) => {
                 eprintln!("{}", e);
-                continue;
+                break;
             }
         };
 
-        match vm.run(&tree) {
-            Ok(v) => match v {
-                Node::Empty | Node::Symbol(_) => {}
-                _ => println!("{}", v),
-            },
-            Err(e) => eprintln!("{}", e),
+        form.push_str(&line);
+        form.push('\n');
+        if !reader::is_complete(&form) {
+            continue;
+        }
+
+        // Blank lines and comments have nothing to run
+        let input = mem::take(&mut form);
+        if reader::flatten(&input).is_empty() {
+            continue;
+        }
+        if let Err(e) = run_line(&mut vm, &input) {
+            eprintln!("{}", e);
+            match options::take_failed_status() {
+                Some(status) => exit_shell(&mut vm, status),
+                None => set_last_status(&mut vm, 1),
+            }
         }
     }
+
+    if !reader::flatten(&form).is_empty() {
+        eprintln!("Unexpected end of input");
+        set_last_status(&mut vm, 1);
+    }
+
+    let status = last_status(&mut vm);
+    exit_shell(&mut vm, status);
+}
+
+/// Parse and run a line of input, printing its value. Outer parentheses are
+/// added if they're missing.
+fn run_line(vm: &mut VM, line: &str) -> Result<(), String> {
+    // Only a failure in this form can end the shell because of errexit
+    options::clear_failed_status();
+
+    let line = if line.trim_start().starts_with('(') {
+        line.to_owned()
+    } else {
+        format!("({})", line)
+    };
+
+    let mut line_iter = line.bytes();
+    let mut str_iter = ByteIter::new(&mut line_iter);
+    let mut lex = Lexer::new(&mut str_iter, "<shell>");
+    let parser = Parser::new(&mut lex);
+    let tree = parser.parse().map_err(|e| e.to_string())?;
+
+    match vm.run(&tree)? {
+        Node::Empty | Node::Symbol(_) => {}
+        v => println!("{}", v),
+    }
+    Ok(())
+}
+
+/// Read lines until a complete s-expression is entered. Continuation lines use
+/// the prompt2 function for their prompt.
+fn read_form(term: &mut Terminal, vm: &mut VM) -> io::Result<String> {
+    let prompt = get_prompt(vm, "prompt", DEFAULT_PROMPT);
+    let mut form = term.readline(&prompt, &mut ShellCompleter::new(vm))?;
+
+    while !reader::is_complete(&form) {
+        let prompt = get_prompt(vm, "prompt2", DEFAULT_PROMPT2);
+        let line = term.readline(&prompt, &mut ShellCompleter::new(vm))?;
+        form.push('\n');
+        form.push_str(&line);
+    }
+
+    term.add_history(&reader::flatten(&form));
+    Ok(form)
+}
+
+fn get_prompt(vm: &mut VM, func_name: &str, default: &str) -> String {
+    let prompt_func =
+        ConsList::new().append(Symbol::with_value(func_name, Node::empty_list()).into_node());
+
+    match vm.eval_list(&prompt_func) {
+        Ok(node) => match node {
+            Node::String(s) => s,
+            _ => {
+                println!(
+                    "{} didn't return a String, returned {}",
+                    func_name,
+                    node.type_str()
+                );
+                default.to_owned()
+            }
+        },
+        _ => default.to_owned(),
+    }
 }
 
 fn shell_default_prompt(_vm: &mut VM, _args: ConsList<Node>) -> Result<Node, String> {
     Ok(Node::from_string(DEFAULT_PROMPT.to_string()))
 }
 
+fn shell_default_prompt2(_vm: &mut VM, _args: ConsList<Node>) -> Result<Node, String> {
+    Ok(Node::from_string(DEFAULT_PROMPT2.to_string()))
+}
+
+fn shell_bind_key(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let args = args_setup!(args, "bind-key", ==, 2);
+
+    let key = format!("{}", vm.eval(args[0])?);
+    let action = format!("{}", vm.eval(args[1])?);
+    terminal::bind_key(&key, &action)?;
+
+    Ok(Node::Empty)
+}
+
+fn shell_set_edit_mode(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let args = args_setup!(args, "set-edit-mode", ==, 1);
+
+    let mode = format!("{}", vm.eval(args[0])?);
+    terminal::set_edit_mode(mode.trim_start_matches(':'))?;
+
+    Ok(Node::Empty)
+}
+
 fn shell_exit(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
     let args = args_setup!(args);
     let status = if !args.is_empty() {
@@ -212,7 +576,7 @@ fn shell_exit(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
         0 // No argument
     };
 
-    ::std::process::exit(status);
+    exit_shell(vm, status as i64);
 }
 
 fn shell_pwd(_vm: &mut VM, _args: ConsList<Node>) -> Result<Node, String> {
@@ -225,36 +589,6 @@ fn shell_pwd(_vm: &mut VM, _args: ConsList<Node>) -> Result<Node, String> {
     Ok(n)
 }
 
-fn shell_cd(vmc: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
-    let args = args_setup!(args, "cd", ==, 1);
-
-    let evaled_arg = vmc.eval(args[0])?;
-
-    let new_path_str = match &evaled_arg {
-        Node::String(s) => tilde(s).into_owned(),
-        Node::Symbol(sym) => match sym.borrow().value() {
-            Node::String(s) => tilde(&s).into_owned(),
-            _ => return Err("cd expected a string".to_owned()),
-        },
-        _ => "".to_owned(),
-    };
-
-    let new_path_segment = path::Path::new(&new_path_str);
-
-    let res = env::set_current_dir(if new_path_segment.is_absolute() {
-        new_path_segment.absolutize().unwrap().to_path_buf()
-    } else {
-        let pwd = env::current_dir().unwrap_or_default();
-        let new_path = pwd.join(new_path_segment);
-        new_path.absolutize().unwrap().to_path_buf()
-    });
-
-    match res {
-        Ok(_) => Ok(Node::Empty),
-        Err(e) => Err(format!("{}", e)),
-    }
-}
-
 fn shell_glob(vmc: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
     let args = args_setup!(args, "glob", >=, 1);
 
@@ -367,50 +701,128 @@ fn set_interactive(vm: &mut VM, i: bool) {
     }
 }
 
+// The status of a capc command is in its result, so it doesn't trigger errexit.
 fn shell_captured_call(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let interactive = is_interactive(vm);
     set_interactive(vm, false);
-    let res = shell_call(vm, args);
-    set_interactive(vm, true);
+    let res = options::without_errexit(|| shell_call(vm, args));
+    set_interactive(vm, interactive);
     res
 }
 
+fn command_line(name: &str, args: &[OsString]) -> String {
+    let mut line = name.to_owned();
+    for arg in args {
+        line.push(' ');
+        line.push_str(&arg.to_string_lossy());
+    }
+    line
+}
+
+fn list_from_nodes(nodes: &[&Node]) -> ConsList<Node> {
+    nodes
+        .iter()
+        .rev()
+        .fold(ConsList::new(), |list, n| list.append((*n).clone()))
+}
+
+fn node_list(nodes: Vec<Node>) -> Node {
+    Node::List(
+        nodes
+            .into_iter()
+            .rev()
+            .fold(ConsList::new(), |list, n| list.append(n)),
+    )
+}
+
+// Bind command line arguments to argv, argc, and script-name.
+fn set_args(vm: &mut VM, script_name: &str, args: &[String]) {
+    let argv = args.iter().rev().fold(ConsList::new(), |list, a| {
+        list.append(Node::from_string(a.to_owned()))
+    });
+    vm.add_symbol(Symbol::with_value("argv", Node::List(argv)).into_ref());
+    vm.add_symbol(Symbol::with_value("argc", Node::Number(args.len() as i64)).into_ref());
+    vm.add_symbol(
+        Symbol::with_value("script-name", Node::from_string(script_name.to_owned())).into_ref(),
+    );
+}
+
+fn last_status(vm: &mut VM) -> i64 {
+    let node = vm.symbols.borrow().get_symbol("last-status");
+    let status = node.borrow().value();
+    match status {
+        Node::Number(n) => n & 0xff,
+        _ => 0,
+    }
+}
+
+fn set_last_status(vm: &mut VM, status: i64) {
+    vm.add_symbol(Symbol::with_value("last-status", Node::Number(status)).into_ref());
+}
+
+// Set pipestatus to the status of each stage of a pipeline and last-status to
+// the status of the last stage, or of the first failing stage with pipefail. A
+// failure aborts evaluation when errexit is set.
+fn set_pipeline_status(vm: &mut VM, statuses: &[i64]) -> Result<(), String> {
+    let pipestatus = node_list(statuses.iter().map(|s| Node::Number(*s)).collect());
+    vm.add_symbol(Symbol::with_value("pipestatus", pipestatus).into_ref());
+
+    let status = if options::is_set("pipefail") {
+        statuses.iter().copied().find(|s| *s != 0).unwrap_or(0)
+    } else {
+        statuses.last().copied().unwrap_or(0)
+    };
+    set_last_status(vm, status);
+    options::check_errexit(status)
+}
+
 fn shell_call(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
     let args = args_setup!(args, "call", >=, 1);
 
     let command_name = vm.eval(&args[0])?;
-    let mut cmd = Command::new(format!("{}", command_name));
+    if args.len() == 1 && directory::autocd(vm, &format!("{}", command_name))? {
+        set_last_status(vm, 0);
+        return Ok(Node::Empty);
+    }
+
+    let mut cmd = EnvChanges::current().command(&format!("{}", command_name));
+    let mut cmd_args: Vec<OsString> = Vec::with_capacity(args.len());
 
     for arg in args.iter().skip(1) {
-        cmd.arg(format!("{}", vm.eval(arg)?));
+        cmd_args.push(format!("{}", vm.eval(arg)?).into());
     }
+    cmd.args(&cmd_args);
 
-    if is_interactive(vm) {
-        match cmd.status() {
-            Ok(out) => {
-                vm.add_symbol(
-                    Symbol::with_value(
-                        "last-status",
-                        Node::Number(i64::from(out.code().unwrap_or(255))),
-                    )
-                    .into_ref(),
-                );
+    let streams = Streams::current()?;
+
+    if jobs::is_background() || is_interactive(vm) {
+        let command = command_line(&format!("{}", command_name), &cmd_args);
+
+        match jobs::launch(vec![cmd], streams, command) {
+            Ok(states) => {
+                let statuses: Vec<i64> = states.iter().map(|s| s.code()).collect();
+                set_pipeline_status(vm, &statuses)?;
                 Ok(Node::Empty)
             }
             Err(e) => {
-                vm.add_symbol(Symbol::with_value("last_status", Node::Number(255)).into_ref());
+                set_last_status(vm, 255);
 
                 match e.kind() {
-                    ErrorKind::NotFound => Err(format!("Command not found {}", command_name)),
+                    ErrorKind::NotFound => {
+                        Err(commands::not_found_error(&format!("{}", command_name)))
+                    }
                     _ => Err(format!("{}", e)),
                 }
             }
         }
     } else {
         let mut map = HashMap::new();
-        cmd.stdin(Stdio::null());
 
-        match cmd.output() {
+        match pipeline::run(vm, vec![Stage::Command(cmd)], streams, true) {
             Ok(out) => {
+                let status = out.statuses.last().copied().unwrap_or(255);
+                set_pipeline_status(vm, &[status])?;
+
                 map.insert(
                     ":stdout".to_owned(),
                     Node::String(
@@ -423,18 +835,17 @@ fn shell_call(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
                 map.insert(
                     ":stderr".to_owned(),
                     Node::String(
-                        String::from_utf8(out.stderr)
+                        String::from_utf8(out.stderr.concat())
                             .unwrap_or_default()
                             .trim()
                             .to_owned(),
                     ),
                 );
-                map.insert(
-                    ":status".to_owned(),
-                    Node::Number(i64::from(out.status.code().unwrap_or(255))),
-                );
+                map.insert(":status".to_owned(), Node::Number(status));
             }
             Err(e) => {
+                set_pipeline_status(vm, &[255])?;
+
                 map.insert(":stdout".to_owned(), Node::String("".to_owned()));
                 map.insert(":stderr".to_owned(), Node::String(format!("{}", e)));
                 map.insert(":status".to_owned(), Node::Number(255));
@@ -447,116 +858,203 @@ fn shell_call(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
 fn shell_pipe(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
     let args = args_setup!(args, "pipe", >=, 1);
 
-    let mut parent_cmd = if let Node::List(l) = args[0] {
-        let mut args: Vec<OsString> = Vec::new();
-        for arg in l.iter().skip(1) {
-            args.push(format!("{}", vm.eval(arg)?).into());
+    let env_changes = EnvChanges::current();
+    let mut stages = Vec::with_capacity(args.len());
+    let mut command_lines = Vec::with_capacity(args.len());
+    // Set by :string for the following Lisp stage
+    let mut whole = false;
+    // Set by :fn, the following list is evaluated to get a function
+    let mut lisp_stage = false;
+
+    for stage in args.iter() {
+        if is_keyword(stage, ":string") {
+            whole = true;
+            continue;
+        }
+        if is_keyword(stage, ":fn") {
+            lisp_stage = true;
+            continue;
         }
 
-        duct::cmd(
-            format!("{}", vm.eval(l.head().unwrap_or(&Node::Empty))?),
-            &args,
-        )
-    } else {
-        return Err(format!(
-            "pipe args must by lists, got {}",
-            args[0].type_str()
-        ));
-    };
+        match stage {
+            Node::List(l) if !lisp_stage && !is_lambda(l) => {
+                let name = format!("{}", vm.eval(l.head().unwrap_or(&Node::Empty))?);
+                let mut args: Vec<OsString> = Vec::new();
+                for arg in l.iter().skip(1) {
+                    args.push(format!("{}", vm.eval(arg)?).into());
+                }
 
-    for cmd in args.iter().skip(1) {
-        parent_cmd = parent_cmd.pipe(if let Node::List(l) = cmd {
-            let mut args: Vec<OsString> = Vec::new();
-            for arg in l.iter().skip(1) {
-                args.push(format!("{}", vm.eval(arg)?).into());
+                command_lines.push(command_line(&name, &args));
+                let mut cmd = env_changes.command(&name);
+                cmd.args(args);
+                stages.push(Stage::Command(cmd));
             }
-
-            duct::cmd(
-                format!("{}", vm.eval(l.head().unwrap_or(&Node::Empty))?),
-                &args,
-            )
-        } else {
-            return Err(format!("pipe args must by lists, got {}", cmd.type_str()));
-        });
+            _ => {
+                let func = pipe_function(vm, stage)?;
+                stages.push(Stage::Lisp { func, whole });
+                whole = false;
+                lisp_stage = false;
+            }
+        }
     }
 
-    let mut map = HashMap::new();
-    parent_cmd = parent_cmd.unchecked();
+    let streams = Streams::current()?;
+    let has_lisp = stages.iter().any(|s| matches!(s, Stage::Lisp { .. }));
 
-    if is_interactive(vm) {
-        match parent_cmd.run() {
-            Ok(out) => {
-                vm.add_symbol(
-                    Symbol::with_value(
-                        "last-status",
-                        Node::Number(i64::from(out.status.code().unwrap_or(255))),
-                    )
-                    .into_ref(),
-                );
+    if jobs::is_background() && has_lisp {
+        return Err("pipe with Lisp stages can't run in the background".to_owned());
+    }
+
+    if (jobs::is_background() || is_interactive(vm)) && !has_lisp {
+        let cmds = stages
+            .into_iter()
+            .filter_map(|s| match s {
+                Stage::Command(cmd) => Some(cmd),
+                Stage::Lisp { .. } => None,
+            })
+            .collect();
+
+        return match jobs::launch(cmds, streams, command_lines.join(" | ")) {
+            Ok(states) => {
+                let statuses: Vec<i64> = states.iter().map(|s| s.code()).collect();
+                set_pipeline_status(vm, &statuses)?;
                 Ok(Node::Empty)
             }
             Err(e) => {
-                vm.add_symbol(Symbol::with_value("last-status", Node::Number(255)).into_ref());
+                set_last_status(vm, 255);
                 Err(format!("{}", e))
             }
-        }
-    } else {
-        parent_cmd = parent_cmd.stdin_null();
-        parent_cmd = parent_cmd.stdout_capture();
-        parent_cmd = parent_cmd.stderr_capture();
+        };
+    }
 
-        match parent_cmd.run() {
-            Ok(out) => {
-                map.insert(
-                    ":stdout".to_owned(),
-                    Node::String(String::from_utf8(out.stdout).unwrap_or_default()),
-                );
-                map.insert(
-                    ":stderr".to_owned(),
-                    Node::String(String::from_utf8(out.stderr).unwrap_or_default()),
-                );
-                map.insert(
-                    ":status".to_owned(),
-                    Node::Number(i64::from(out.status.code().unwrap_or(255))),
-                );
+    // Lisp stages run in the shell so the pipeline isn't a job, its output
+    // is only captured when the shell isn't interactive.
+    let capture = !is_interactive(vm);
+    let mut map = HashMap::new();
+    match pipeline::run(vm, stages, streams, capture) {
+        Ok(out) => {
+            set_pipeline_status(vm, &out.statuses)?;
+            if let Some(e) = out.error {
+                return Err(e);
             }
-            Err(e) => {
-                map.insert(":stdout".to_owned(), Node::String("".to_owned()));
-                map.insert(":stderr".to_owned(), Node::String(format!("{}", e)));
-                map.insert(":status".to_owned(), Node::Number(255));
+            if !capture {
+                return Ok(Node::Empty);
             }
+
+            let stage_stderr: Vec<String> = out
+                .stderr
+                .into_iter()
+                .map(|e| String::from_utf8(e).unwrap_or_default())
+                .collect();
+            map.insert(
+                ":stdout".to_owned(),
+                Node::String(String::from_utf8(out.stdout).unwrap_or_default()),
+            );
+            map.insert(":stderr".to_owned(), Node::String(stage_stderr.concat()));
+            map.insert(
+                ":stage-stderr".to_owned(),
+                node_list(stage_stderr.into_iter().map(Node::String).collect()),
+            );
+            map.insert(":status".to_owned(), Node::Number(last_status(vm)));
+            map.insert(
+                ":pipestatus".to_owned(),
+                node_list(out.statuses.into_iter().map(Node::Number).collect()),
+            );
+        }
+        Err(e) => {
+            set_pipeline_status(vm, &[255])?;
+
+            map.insert(":stdout".to_owned(), Node::String("".to_owned()));
+            map.insert(":stderr".to_owned(), Node::String(format!("{}", e)));
+            map.insert(":status".to_owned(), Node::Number(255));
         }
-        Ok(Node::from_hashmap(map))
     }
+    Ok(Node::from_hashmap(map))
 }
 
-fn shell_export(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
-    let args = args_setup!(args, "export", ==, 1);
+fn is_lambda(l: &ConsList<Node>) -> bool {
+    matches!(l.head(), Some(Node::Symbol(sym)) if sym.borrow().name() == "lambda")
+}
+
+// Evaluate a Lisp pipeline stage, it must return something that can be called.
+fn pipe_function(vm: &mut VM, stage: &Node) -> Result<Node, String> {
+    let func = match stage {
+        Node::Symbol(_) if is_callable(vm, stage) => stage.clone(),
+        _ => vm.eval(stage)?,
+    };
 
-    if let Node::Symbol(s) = vm.eval(&args[0])? {
-        let sym_name = s.borrow().name().to_owned();
-        let real_sym = vm.symbols.borrow().get_symbol(&sym_name);
-        let real_sym_b = real_sym.borrow();
-        let value = format!("{}", real_sym_b.value());
-        env::set_var(sym_name, value);
-        Ok(Node::Empty)
+    if is_callable(vm, &func) {
+        Ok(func)
     } else {
-        Err("export requires a Symbol as the first argument".to_owned())
+        Err(format!(
+            "pipe stages must be commands or functions, got {}",
+            func.type_str()
+        ))
     }
 }
 
-fn shell_unexport(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
-    let args = args_setup!(args, "unexport", ==, 1);
+// A value call_function can call.
+fn is_callable(vm: &mut VM, node: &Node) -> bool {
+    match node {
+        Node::Function(_) => true,
+        Node::Symbol(sym) => {
+            let sym = vm.symbols.borrow().get_symbol(sym.borrow().name());
+            let bound = !matches!(sym.borrow().function, Callable::None);
+            bound
+        }
+        _ => false,
+    }
+}
 
-    if let Node::Symbol(s) = vm.eval(&args[0])? {
-        env::remove_var(s.borrow().name());
-        Ok(Node::Empty)
-    } else {
-        Err("export requires a Symbol as the first argument".to_owned())
+// Whether a node is the keyword, or symbol, with the given name.
+fn is_keyword(node: &Node, name: &str) -> bool {
+    match node {
+        Node::Keyword(k) => k == name,
+        Node::Symbol(sym) => sym.borrow().name() == name,
+        _ => false,
+    }
+}
+
+// Name defined by the first argument of define or setf, which may be quoted.
+fn defined_name(node: &Node) -> Option<String> {
+    match node {
+        Node::Symbol(sym) => Some(sym.borrow().name().to_owned()),
+        Node::List(l) => match l.iter().nth(1) {
+            Some(Node::Symbol(sym)) => Some(sym.borrow().name().to_owned()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn shell_setf(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let name = args.head().and_then(defined_name);
+    if let Some(name) = &name {
+        commands::add_name(name);
+    }
+
+    let ret = vm.eval_list(&args.append(Symbol::new("_setf").into_node()));
+    // Changing an exported variable changes it for children too
+    if let Some(name) = &name {
+        environment::sync_var(vm, name);
+    }
+    ret
+}
+
+fn shell_define_syntax(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    if let Some(name) = args.head().and_then(defined_name) {
+        commands::add_name(&name);
     }
+
+    vm.eval_list(&args.append(Symbol::new("_define-syntax").into_node()))
 }
 
 fn shell_define(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let name = args.head().and_then(defined_name);
+    if let Some(name) = &name {
+        commands::add_name(name);
+    }
+
     set_interactive(vm, false);
 
     let new_args = args.append(Symbol::new("_define").into_node());
@@ -564,5 +1062,8 @@ fn shell_define(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
 
     set_interactive(vm, true);
 
+    if let Some(name) = &name {
+        environment::sync_var(vm, name);
+    }
     ret
 }
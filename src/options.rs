@@ -0,0 +1,82 @@
+use lazuli_vm::args_setup;
+use lazuli_vm::object::cons_list::ConsList;
+use lazuli_vm::object::Node;
+use lazuli_vm::vm::VM;
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeSet;
+
+/// Options that can be changed with set-option. All are off by default.
+const OPTIONS: [&str; 4] = [
+    // Change to a directory entered as a command
+    "autocd",
+    // Abort evaluation when an external command fails
+    "errexit",
+    // Scripts exit with last-status instead of 0
+    "exit-last-status",
+    // A pipeline's status is the status of its first failing stage
+    "pipefail",
+];
+
+thread_local! {
+    static ENABLED: RefCell<BTreeSet<&'static str>> = const { RefCell::new(BTreeSet::new()) };
+    // Status of the command that triggered errexit
+    static FAILED_STATUS: Cell<Option<i64>> = const { Cell::new(None) };
+    // Set while running a command whose status is returned to the caller
+    static SUSPENDED: Cell<bool> = const { Cell::new(false) };
+}
+
+pub fn is_set(name: &str) -> bool {
+    ENABLED.with(|o| o.borrow().contains(name))
+}
+
+/// Returns an error if errexit is set and the status is a failure.
+pub fn check_errexit(status: i64) -> Result<(), String> {
+    if status == 0 || !is_set("errexit") || SUSPENDED.with(Cell::get) {
+        return Ok(());
+    }
+
+    FAILED_STATUS.with(|s| s.set(Some(status)));
+    Err(format!("Command failed with status {}", status))
+}
+
+/// Run a function without errexit, for commands that are explicitly captured.
+pub fn without_errexit<T>(f: impl FnOnce() -> T) -> T {
+    let suspended = SUSPENDED.with(|s| s.replace(true));
+    let ret = f();
+    SUSPENDED.with(|s| s.set(suspended));
+    ret
+}
+
+/// Forget a failure from an earlier top-level form.
+pub fn clear_failed_status() {
+    FAILED_STATUS.with(|s| s.set(None));
+}
+
+/// The status of the command that aborted evaluation because of errexit.
+pub fn take_failed_status() -> Option<i64> {
+    FAILED_STATUS.with(|s| s.take())
+}
+
+pub fn shell_set_option(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let args = args_setup!(args, "set-option", ==, 2);
+
+    let name = format!("{}", vm.eval(args[0])?);
+    let name = name.trim_start_matches(':');
+    let option = match OPTIONS.iter().find(|o| **o == name) {
+        Some(option) => *option,
+        None => return Err(format!("set-option unknown option {}", name)),
+    };
+
+    let enable = vm.eval(args[1])?.is_truthy();
+    ENABLED.with(|o| {
+        let mut o = o.borrow_mut();
+        if enable {
+            o.insert(option);
+        } else {
+            o.remove(option);
+        }
+    });
+
+    Ok(Node::Empty)
+}
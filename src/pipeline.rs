@@ -0,0 +1,301 @@
+use crate::call_function;
+use crate::jobs;
+use crate::redirect::Streams;
+
+use lazuli_vm::object::Node;
+use lazuli_vm::vm::VM;
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::fd::{AsFd, OwnedFd};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+
+/// A stage of a pipeline.
+pub enum Stage {
+    Command(Command),
+    /// A Lisp function called with each line of input, or with all of it as a
+    /// single string when `whole` is set. Its return values are the output.
+    Lisp {
+        func: Node,
+        whole: bool,
+    },
+}
+
+/// Output of a pipeline, the captured streams are empty if they weren't
+/// captured or were redirected. Stderr and the exit status are kept for each
+/// stage.
+pub struct PipelineOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<Vec<u8>>,
+    pub statuses: Vec<i64>,
+    /// Error from a Lisp stage, the stage's status is 1.
+    pub error: Option<String>,
+}
+
+// Where a Lisp stage writes its output.
+enum LispOutput {
+    // Written to the next stage by a thread so the shell never blocks on a
+    // full pipe while it's the only one that could empty it.
+    Pipe(mpsc::Sender<Vec<u8>>),
+    File(File),
+    Stdout,
+    Capture,
+}
+
+impl LispOutput {
+    // Returns false once there's nothing left to write to.
+    fn write(&mut self, data: Vec<u8>, captured: &mut Vec<u8>) -> bool {
+        match self {
+            LispOutput::Pipe(tx) => tx.send(data).is_ok(),
+            LispOutput::File(f) => f.write_all(&data).is_ok(),
+            LispOutput::Stdout => io::stdout().write_all(&data).is_ok(),
+            LispOutput::Capture => {
+                captured.extend(data);
+                true
+            }
+        }
+    }
+}
+
+struct LispStage {
+    index: usize,
+    func: Node,
+    whole: bool,
+    input: Option<File>,
+    output: LispOutput,
+}
+
+fn pipe_writer(writer: os_pipe::PipeWriter) -> mpsc::Sender<Vec<u8>> {
+    let (tx, rx) = mpsc::channel::<Vec<u8>>();
+    thread::spawn(move || {
+        let mut writer = writer;
+        for data in rx {
+            if writer.write_all(&data).is_err() {
+                break;
+            }
+        }
+    });
+    tx
+}
+
+fn read_thread<R: Read + Send + 'static>(mut reader: R) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = reader.read_to_end(&mut buf);
+        buf
+    })
+}
+
+/// Run a pipeline, a single command is a pipeline of one. When captured, any
+/// stream that isn't redirected is read into the output and stdin defaults to
+/// empty. If stderr is joined to stdout, both are read from the same pipe.
+/// External commands run concurrently, Lisp stages run in order in the shell.
+pub fn run(
+    vm: &mut VM,
+    stages: Vec<Stage>,
+    streams: Streams,
+    capture: bool,
+) -> io::Result<PipelineOutput> {
+    let _guard = jobs::SignalGuard::new();
+    let Streams {
+        stdin,
+        stdout,
+        stderr,
+        stderr_to_stdout,
+    } = streams;
+
+    let joined = if capture && stderr_to_stdout && stdout.is_none() {
+        Some(os_pipe::pipe()?)
+    } else {
+        None
+    };
+
+    let count = stages.len();
+    let last = count.saturating_sub(1);
+    let mut input = stdin;
+    let mut children: Vec<(usize, Child)> = Vec::with_capacity(count);
+    let mut lisp_stages = Vec::new();
+    let mut stdout_thread = None;
+    let mut stderr_threads = Vec::with_capacity(count);
+
+    for (i, stage) in stages.into_iter().enumerate() {
+        let mut cmd = match stage {
+            Stage::Command(cmd) => cmd,
+            Stage::Lisp { func, whole } => {
+                // The first stage reads the shell's stdin unless captured
+                let stage_input = match input.take() {
+                    Some(f) => Some(f),
+                    None if !capture => Some(File::from(io::stdin().as_fd().try_clone_to_owned()?)),
+                    None => None,
+                };
+
+                let output = if i < last {
+                    let (reader, writer) = os_pipe::pipe()?;
+                    input = Some(File::from(OwnedFd::from(reader)));
+                    LispOutput::Pipe(pipe_writer(writer))
+                } else if let Some((_, writer)) = &joined {
+                    LispOutput::File(File::from(OwnedFd::from(writer.try_clone()?)))
+                } else if let Some(f) = &stdout {
+                    LispOutput::File(f.try_clone()?)
+                } else if capture {
+                    LispOutput::Capture
+                } else {
+                    LispOutput::Stdout
+                };
+
+                lisp_stages.push(LispStage {
+                    index: i,
+                    func,
+                    whole,
+                    input: stage_input,
+                    output,
+                });
+                continue;
+            }
+        };
+
+        jobs::reset_child_signals(&mut cmd);
+
+        match input.take() {
+            Some(f) => cmd.stdin(f),
+            None if capture => cmd.stdin(Stdio::null()),
+            None => cmd.stdin(Stdio::inherit()),
+        };
+
+        if i < last {
+            cmd.stdout(Stdio::piped());
+        } else if let Some((_, writer)) = &joined {
+            cmd.stdout(writer.try_clone()?);
+        } else if let Some(f) = &stdout {
+            cmd.stdout(f.try_clone()?);
+        } else if capture {
+            cmd.stdout(Stdio::piped());
+        }
+
+        if let Some((_, writer)) = &joined {
+            cmd.stderr(writer.try_clone()?);
+        } else if let Some(f) = &stderr {
+            cmd.stderr(f.try_clone()?);
+        } else if capture {
+            cmd.stderr(Stdio::piped());
+        }
+
+        match cmd.spawn() {
+            Ok(mut child) => {
+                if let Some(out) = child.stdout.take() {
+                    if i < last {
+                        input = Some(File::from(OwnedFd::from(out)));
+                    } else {
+                        stdout_thread = Some(read_thread(out));
+                    }
+                }
+                // Stderr is read on its own thread so a full pipe can't block
+                // the pipeline.
+                stderr_threads.push((i, child.stderr.take().map(read_thread)));
+                children.push((i, child));
+            }
+            Err(e) => {
+                // Reap anything that did start, it will exit once its pipe closes
+                drop(input);
+                drop(lisp_stages);
+                for (_, child) in children {
+                    let _ = jobs::wait_child(child.id());
+                }
+                return Err(e);
+            }
+        }
+    }
+    drop(input);
+
+    // The commands hold the write ends of the joined pipe, drop ours so
+    // reading can finish once they exit.
+    if let Some((reader, writer)) = joined {
+        drop(writer);
+        stdout_thread = Some(read_thread(reader));
+    }
+
+    let mut captured = Vec::new();
+    let mut statuses = vec![0; count];
+    let mut error = None;
+    for stage in lisp_stages {
+        let index = stage.index;
+        if let Err(e) = run_lisp_stage(vm, stage, &mut captured) {
+            statuses[index] = 1;
+            error.get_or_insert(e);
+        }
+    }
+
+    if let Some(t) = stdout_thread {
+        captured.extend(t.join().unwrap_or_default());
+    }
+
+    let mut stage_stderr = vec![Vec::new(); count];
+    for (i, t) in stderr_threads {
+        if let Some(t) = t {
+            stage_stderr[i] = t.join().unwrap_or_default();
+        }
+    }
+
+    for (i, child) in children {
+        statuses[i] = jobs::status_code(jobs::wait_child(child.id())?);
+    }
+
+    Ok(PipelineOutput {
+        stdout: captured,
+        stderr: stage_stderr,
+        statuses,
+        error,
+    })
+}
+
+// Call a Lisp stage's function with its input. Values that aren't truthy
+// produce no output so a function can act as a filter.
+fn run_lisp_stage(vm: &mut VM, stage: LispStage, captured: &mut Vec<u8>) -> Result<(), String> {
+    let LispStage {
+        func,
+        whole,
+        input,
+        mut output,
+        ..
+    } = stage;
+
+    let mut reader: Box<dyn BufRead> = match input {
+        Some(f) => Box::new(BufReader::new(f)),
+        None => Box::new(io::empty()),
+    };
+
+    if whole {
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).map_err(|e| format!("{}", e))?;
+        let text = String::from_utf8_lossy(&buf).into_owned();
+        let value = call_function(vm, &func, vec![Node::from_string(text)])?;
+        if value.is_truthy() {
+            output.write(format!("{}", value).into_bytes(), captured);
+        }
+        return Ok(());
+    }
+
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        if reader
+            .read_until(b'\n', &mut buf)
+            .map_err(|e| format!("{}", e))?
+            == 0
+        {
+            return Ok(());
+        }
+        if buf.last() == Some(&b'\n') {
+            buf.pop();
+        }
+
+        let line = String::from_utf8_lossy(&buf).into_owned();
+        let value = call_function(vm, &func, vec![Node::from_string(line)])?;
+        if value.is_truthy() && !output.write(format!("{}\n", value).into_bytes(), captured) {
+            // The next stage stopped reading
+            return Ok(());
+        }
+    }
+}
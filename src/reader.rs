@@ -0,0 +1,149 @@
+// Helpers for reading s-expressions that span multiple lines.
+
+use std::io::{self, Read};
+
+/// Returns false if the source has unclosed parentheses or an unterminated string.
+pub fn is_complete(src: &str) -> bool {
+    let mut depth: i64 = 0;
+    let mut in_string = false;
+    let mut in_comment = false;
+    let mut escaped = false;
+
+    for c in src.chars() {
+        if in_comment {
+            in_comment = c != '\n';
+        } else if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+        } else {
+            match c {
+                '"' => in_string = true,
+                ';' => in_comment = true,
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                _ => {}
+            }
+        }
+    }
+
+    depth <= 0 && !in_string
+}
+
+/// Join a multi-line form into a single equivalent line. Comments are dropped
+/// and newlines inside strings are escaped so the form fits on one history line.
+pub fn flatten(src: &str) -> String {
+    let mut line = String::with_capacity(src.len());
+    let mut in_string = false;
+    let mut in_comment = false;
+    let mut escaped = false;
+
+    for c in src.chars() {
+        if in_comment {
+            if c == '\n' {
+                in_comment = false;
+                line.push(' ');
+            }
+        } else if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+
+            if c == '\n' {
+                line.push_str("\\n");
+            } else {
+                line.push(c);
+            }
+        } else {
+            match c {
+                '"' => {
+                    in_string = true;
+                    line.push(c);
+                }
+                ';' => in_comment = true,
+                '\n' => line.push(' '),
+                _ => line.push(c),
+            }
+        }
+    }
+
+    line.trim().to_owned()
+}
+
+/// Read a line a byte at a time so nothing past it is consumed, the newline
+/// isn't included. Returns None at the end of input.
+pub fn read_line<R: Read>(input: &mut R) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    let mut byte = [0; 1];
+
+    loop {
+        match input.read(&mut byte) {
+            Ok(0) if line.is_empty() => return Ok(None),
+            Ok(0) => break,
+            Ok(_) if byte[0] == b'\n' => break,
+            Ok(_) => line.push(byte[0]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn complete_forms() {
+        assert!(is_complete("(ls -la)"));
+        assert!(is_complete("ls -la"));
+        assert!(!is_complete("(echo (pwd)"));
+        assert!(!is_complete("(echo \"a"));
+    }
+
+    #[test]
+    fn complete_ignores_strings_and_comments() {
+        assert!(is_complete("(echo \")\")"));
+        assert!(!is_complete("(echo \"(\""));
+        assert!(is_complete("(echo \"\\\"(\")"));
+        assert!(!is_complete("(echo ; )\n"));
+        assert!(is_complete("(echo ; (\n)"));
+    }
+
+    #[test]
+    fn flatten_joins_lines() {
+        assert_eq!(flatten("(echo\n  1\n  2)\n"), "(echo   1   2)");
+    }
+
+    #[test]
+    fn flatten_escapes_newlines_in_strings() {
+        assert_eq!(flatten("(echo \"a\nb\")"), "(echo \"a\\nb\")");
+        assert_eq!(flatten("(echo \"a\\\"\nb\")"), "(echo \"a\\\"\\nb\")");
+    }
+
+    #[test]
+    fn read_line_stops_at_newline() {
+        let mut input: &[u8] = b"(echo 1)\n(cat)\nrest";
+        assert_eq!(read_line(&mut input).unwrap().as_deref(), Some("(echo 1)"));
+        assert_eq!(read_line(&mut input).unwrap().as_deref(), Some("(cat)"));
+        assert_eq!(input, b"rest");
+        assert_eq!(read_line(&mut input).unwrap().as_deref(), Some("rest"));
+        assert_eq!(read_line(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn flatten_drops_comments() {
+        assert_eq!(flatten("(echo 1) ; one\n(echo 2)"), "(echo 1)  (echo 2)");
+        assert_eq!(flatten("(echo \";\")"), "(echo \";\")");
+        assert_eq!(flatten("; only a comment"), "");
+    }
+}
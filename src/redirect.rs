@@ -0,0 +1,164 @@
+use crate::set_last_status;
+
+use lazuli_vm::args_setup;
+use lazuli_vm::object::cons_list::ConsList;
+use lazuli_vm::object::Node;
+use lazuli_vm::vm::VM;
+use shellexpand::tilde;
+
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+
+// Redirections are opened when a redirect form is entered and stay active for
+// every command evaluated in its body. Inner forms take precedence over outer ones.
+thread_local! {
+    static REDIRECTS: RefCell<Vec<Redirect>> = const { RefCell::new(Vec::new()) };
+}
+
+enum Redirect {
+    Stdin(File),
+    Stdout(File),
+    Stderr(File),
+    StderrToStdout,
+}
+
+/// The resolved standard streams for a command after applying all active redirections.
+#[derive(Default)]
+pub struct Streams {
+    pub stdin: Option<File>,
+    pub stdout: Option<File>,
+    pub stderr: Option<File>,
+    pub stderr_to_stdout: bool,
+}
+
+impl Streams {
+    /// Collect the currently active redirections. Each stream gets its own
+    /// handle so the result can be consumed by a single command.
+    pub fn current() -> Result<Self, String> {
+        REDIRECTS.with(|r| {
+            let mut streams = Streams::default();
+
+            for redirect in r.borrow().iter() {
+                match redirect {
+                    Redirect::Stdin(f) => streams.stdin = Some(clone_file(f)?),
+                    Redirect::Stdout(f) => streams.stdout = Some(clone_file(f)?),
+                    Redirect::Stderr(f) => {
+                        streams.stderr = Some(clone_file(f)?);
+                        streams.stderr_to_stdout = false;
+                    }
+                    Redirect::StderrToStdout => {
+                        streams.stderr = None;
+                        streams.stderr_to_stdout = true;
+                    }
+                }
+            }
+
+            if streams.stderr_to_stdout {
+                if let Some(f) = &streams.stdout {
+                    streams.stderr = Some(clone_file(f)?);
+                }
+            }
+
+            Ok(streams)
+        })
+    }
+}
+
+fn clone_file(f: &File) -> Result<File, String> {
+    f.try_clone().map_err(|e| format!("{}", e))
+}
+
+fn open_path(vm: &mut VM, path: &Node, append: bool, write: bool) -> Result<File, String> {
+    let path_str = tilde(&format!("{}", vm.eval(path)?)).into_owned();
+
+    let res = if write {
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .append(append)
+            .truncate(!append)
+            .open(&path_str)
+    } else {
+        File::open(&path_str)
+    };
+
+    res.map_err(|e| {
+        set_last_status(vm, 1);
+        format!("{}: {}", path_str, e)
+    })
+}
+
+fn with_redirects(vm: &mut VM, redirects: Vec<Redirect>, body: &[&Node]) -> Result<Node, String> {
+    let depth = REDIRECTS.with(|r| {
+        let mut r = r.borrow_mut();
+        let depth = r.len();
+        r.extend(redirects);
+        depth
+    });
+
+    let mut ret = Ok(Node::Empty);
+    for form in body {
+        ret = vm.eval(form);
+        if ret.is_err() {
+            break;
+        }
+    }
+
+    REDIRECTS.with(|r| r.borrow_mut().truncate(depth));
+    ret
+}
+
+pub fn shell_redirect_stdout(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let args = args_setup!(args, "1>", >=, 2);
+    let f = open_path(vm, args[0], false, true)?;
+    with_redirects(vm, vec![Redirect::Stdout(f)], &args[1..])
+}
+
+pub fn shell_redirect_stdout_append(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let args = args_setup!(args, "1>>", >=, 2);
+    let f = open_path(vm, args[0], true, true)?;
+    with_redirects(vm, vec![Redirect::Stdout(f)], &args[1..])
+}
+
+pub fn shell_redirect_stdin(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let args = args_setup!(args, "0<", >=, 2);
+    let f = open_path(vm, args[0], false, false)?;
+    with_redirects(vm, vec![Redirect::Stdin(f)], &args[1..])
+}
+
+pub fn shell_redirect_stderr(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let args = args_setup!(args, "2>", >=, 2);
+    let f = open_path(vm, args[0], false, true)?;
+    with_redirects(vm, vec![Redirect::Stderr(f)], &args[1..])
+}
+
+pub fn shell_redirect_stderr_append(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let args = args_setup!(args, "2>>", >=, 2);
+    let f = open_path(vm, args[0], true, true)?;
+    with_redirects(vm, vec![Redirect::Stderr(f)], &args[1..])
+}
+
+pub fn shell_redirect_stderr_to_stdout(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let args = args_setup!(args, "2>&1", >=, 1);
+    with_redirects(vm, vec![Redirect::StderrToStdout], &args)
+}
+
+pub fn shell_redirect_all(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let args = args_setup!(args, "&>", >=, 2);
+    let f = open_path(vm, args[0], false, true)?;
+    with_redirects(
+        vm,
+        vec![Redirect::Stdout(f), Redirect::StderrToStdout],
+        &args[1..],
+    )
+}
+
+pub fn shell_redirect_all_append(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let args = args_setup!(args, "&>>", >=, 2);
+    let f = open_path(vm, args[0], true, true)?;
+    with_redirects(
+        vm,
+        vec![Redirect::Stdout(f), Redirect::StderrToStdout],
+        &args[1..],
+    )
+}
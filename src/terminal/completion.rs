@@ -0,0 +1,92 @@
+use std::io::{self, Write};
+
+use super::width::display_width;
+
+/// Provides completion candidates for the line editor.
+pub trait Completer {
+    /// Return the candidates for the word starting at `word_start` in `line`.
+    /// `line` is the text before the cursor. Candidates replace the whole word.
+    fn complete(&mut self, line: &str, word_start: usize) -> Vec<String>;
+}
+
+pub enum Completion {
+    /// The text before the cursor should be replaced
+    Replace(String),
+    /// More than one candidate matched and no more text could be inserted
+    List(Vec<String>),
+    None,
+}
+
+/// Byte index of the start of the word ending at the end of `line`.
+pub fn word_start(line: &str) -> usize {
+    line.rfind(|c: char| c.is_whitespace() || c == '(' || c == ')' || c == '"')
+        .map(|i| i + 1)
+        .unwrap_or(0)
+}
+
+pub fn complete(line: &str, completer: &mut dyn Completer) -> Completion {
+    let start = word_start(line);
+    let word = &line[start..];
+
+    let mut candidates = completer.complete(line, start);
+    candidates.sort();
+    candidates.dedup();
+
+    match candidates.len() {
+        0 => Completion::None,
+        1 => {
+            let mut new_line = line[..start].to_owned();
+            new_line.push_str(&candidates[0]);
+            if !candidates[0].ends_with('/') {
+                new_line.push(' ');
+            }
+            Completion::Replace(new_line)
+        }
+        _ => {
+            let prefix = common_prefix(&candidates);
+            if prefix.len() > word.len() {
+                let mut new_line = line[..start].to_owned();
+                new_line.push_str(&prefix);
+                Completion::Replace(new_line)
+            } else {
+                Completion::List(candidates)
+            }
+        }
+    }
+}
+
+fn common_prefix(candidates: &[String]) -> String {
+    let mut prefix = candidates[0].as_str();
+    for c in candidates.iter().skip(1) {
+        let len = prefix
+            .char_indices()
+            .zip(c.chars())
+            .take_while(|((_, a), b)| a == b)
+            .last()
+            .map(|((i, a), _)| i + a.len_utf8())
+            .unwrap_or(0);
+        prefix = &prefix[..len];
+    }
+    prefix.to_owned()
+}
+
+/// Print candidates in columns below the current line.
+pub fn print_list<W: Write>(out: &mut W, candidates: &[String]) -> io::Result<()> {
+    let width = termion::terminal_size().map(|(w, _)| w).unwrap_or(80) as usize;
+    let col_width = candidates
+        .iter()
+        .map(|c| display_width(c))
+        .max()
+        .unwrap_or(0)
+        + 2;
+    let cols = (width / col_width).max(1);
+
+    write!(out, "\n\r")?;
+    for (i, c) in candidates.iter().enumerate() {
+        if i > 0 && i % cols == 0 {
+            write!(out, "\n\r")?;
+        }
+        write!(out, "{}{}", c, " ".repeat(col_width - display_width(c)))?;
+    }
+    write!(out, "\n\r")
+}
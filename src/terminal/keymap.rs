@@ -0,0 +1,232 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+
+use termion::event::Key;
+
+/// Editing commands that keys can be bound to. Names follow GNU readline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    AcceptLine,
+    Interrupt,
+    Complete,
+    BeginningOfLine,
+    EndOfLine,
+    BackwardChar,
+    ForwardChar,
+    BackwardWord,
+    ForwardWord,
+    BackwardDeleteChar,
+    DeleteChar,
+    DeleteCharOrEof,
+    KillLine,
+    UnixLineDiscard,
+    UnixWordRubout,
+    KillWord,
+    BackwardKillWord,
+    Yank,
+    YankPop,
+    TransposeChars,
+    ClearScreen,
+    PreviousHistory,
+    NextHistory,
+    ReverseSearchHistory,
+    ForwardSearchHistory,
+}
+
+const ACTION_NAMES: [(&str, Action); 25] = [
+    ("accept-line", Action::AcceptLine),
+    ("interrupt", Action::Interrupt),
+    ("complete", Action::Complete),
+    ("beginning-of-line", Action::BeginningOfLine),
+    ("end-of-line", Action::EndOfLine),
+    ("backward-char", Action::BackwardChar),
+    ("forward-char", Action::ForwardChar),
+    ("backward-word", Action::BackwardWord),
+    ("forward-word", Action::ForwardWord),
+    ("backward-delete-char", Action::BackwardDeleteChar),
+    ("delete-char", Action::DeleteChar),
+    ("delete-char-or-eof", Action::DeleteCharOrEof),
+    ("kill-line", Action::KillLine),
+    ("unix-line-discard", Action::UnixLineDiscard),
+    ("unix-word-rubout", Action::UnixWordRubout),
+    ("kill-word", Action::KillWord),
+    ("backward-kill-word", Action::BackwardKillWord),
+    ("yank", Action::Yank),
+    ("yank-pop", Action::YankPop),
+    ("transpose-chars", Action::TransposeChars),
+    ("clear-screen", Action::ClearScreen),
+    ("previous-history", Action::PreviousHistory),
+    ("next-history", Action::NextHistory),
+    ("reverse-search-history", Action::ReverseSearchHistory),
+    ("forward-search-history", Action::ForwardSearchHistory),
+];
+
+impl Action {
+    pub fn from_name(name: &str) -> Option<Action> {
+        ACTION_NAMES
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, a)| *a)
+    }
+
+    /// Actions that add to the kill ring. Consecutive kills are joined.
+    pub fn is_kill(self) -> bool {
+        matches!(
+            self,
+            Action::KillLine
+                | Action::UnixLineDiscard
+                | Action::UnixWordRubout
+                | Action::KillWord
+                | Action::BackwardKillWord
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditMode {
+    Emacs,
+    Vi,
+}
+
+thread_local! {
+    static EMACS_KEYMAP: RefCell<HashMap<Key, Action>> = RefCell::new(default_emacs_keymap());
+    static EDIT_MODE: Cell<EditMode> = const { Cell::new(EditMode::Emacs) };
+}
+
+fn default_emacs_keymap() -> HashMap<Key, Action> {
+    let mut map = HashMap::new();
+    map.insert(Key::Char('\n'), Action::AcceptLine);
+    map.insert(Key::Char('\t'), Action::Complete);
+    map.insert(Key::Ctrl('c'), Action::Interrupt);
+    map.insert(Key::Ctrl('a'), Action::BeginningOfLine);
+    map.insert(Key::Home, Action::BeginningOfLine);
+    map.insert(Key::Ctrl('e'), Action::EndOfLine);
+    map.insert(Key::End, Action::EndOfLine);
+    map.insert(Key::Ctrl('b'), Action::BackwardChar);
+    map.insert(Key::Left, Action::BackwardChar);
+    map.insert(Key::Ctrl('f'), Action::ForwardChar);
+    map.insert(Key::Right, Action::ForwardChar);
+    map.insert(Key::Alt('b'), Action::BackwardWord);
+    map.insert(Key::Alt('f'), Action::ForwardWord);
+    map.insert(Key::Backspace, Action::BackwardDeleteChar);
+    map.insert(Key::Ctrl('h'), Action::BackwardDeleteChar);
+    map.insert(Key::Delete, Action::DeleteChar);
+    map.insert(Key::Ctrl('d'), Action::DeleteCharOrEof);
+    map.insert(Key::Ctrl('k'), Action::KillLine);
+    map.insert(Key::Ctrl('u'), Action::UnixLineDiscard);
+    map.insert(Key::Ctrl('w'), Action::UnixWordRubout);
+    map.insert(Key::Alt('d'), Action::KillWord);
+    map.insert(Key::Alt('\x7f'), Action::BackwardKillWord);
+    map.insert(Key::Ctrl('y'), Action::Yank);
+    map.insert(Key::Alt('y'), Action::YankPop);
+    map.insert(Key::Ctrl('t'), Action::TransposeChars);
+    map.insert(Key::Ctrl('l'), Action::ClearScreen);
+    map.insert(Key::Ctrl('p'), Action::PreviousHistory);
+    map.insert(Key::Up, Action::PreviousHistory);
+    map.insert(Key::Ctrl('n'), Action::NextHistory);
+    map.insert(Key::Down, Action::NextHistory);
+    map.insert(Key::Ctrl('r'), Action::ReverseSearchHistory);
+    map.insert(Key::Ctrl('s'), Action::ForwardSearchHistory);
+    map
+}
+
+pub fn lookup(key: Key) -> Option<Action> {
+    EMACS_KEYMAP.with(|m| m.borrow().get(&key).copied())
+}
+
+/// Parse a key description such as "C-a", "M-f", "up", or "x".
+pub fn parse_key(spec: &str) -> Option<Key> {
+    let single = |s: &str| {
+        let mut chars = s.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(c),
+            _ => None,
+        }
+    };
+
+    if let Some(rest) = spec.strip_prefix("C-") {
+        return single(rest).map(|c| Key::Ctrl(c.to_ascii_lowercase()));
+    }
+    if let Some(rest) = spec.strip_prefix("M-") {
+        return match rest {
+            "backspace" => Some(Key::Alt('\x7f')),
+            _ => single(rest).map(Key::Alt),
+        };
+    }
+
+    let key = match spec {
+        "up" => Key::Up,
+        "down" => Key::Down,
+        "left" => Key::Left,
+        "right" => Key::Right,
+        "home" => Key::Home,
+        "end" => Key::End,
+        "backspace" => Key::Backspace,
+        "delete" => Key::Delete,
+        "tab" => Key::Char('\t'),
+        "enter" => Key::Char('\n'),
+        "esc" => Key::Esc,
+        _ => return single(spec).map(Key::Char),
+    };
+    Some(key)
+}
+
+/// Bind a key to an editing action by name.
+pub fn bind_key(spec: &str, action: &str) -> Result<(), String> {
+    let key = parse_key(spec).ok_or_else(|| format!("invalid key {}", spec))?;
+    let action = Action::from_name(action).ok_or_else(|| format!("unknown action {}", action))?;
+
+    EMACS_KEYMAP.with(|m| m.borrow_mut().insert(key, action));
+    Ok(())
+}
+
+pub fn edit_mode() -> EditMode {
+    EDIT_MODE.with(|m| m.get())
+}
+
+/// Switch between "emacs" and "vi" editing.
+pub fn set_edit_mode(name: &str) -> Result<(), String> {
+    let mode = match name {
+        "emacs" => EditMode::Emacs,
+        "vi" => EditMode::Vi,
+        _ => return Err(format!("unknown edit mode {}", name)),
+    };
+
+    EDIT_MODE.with(|m| m.set(mode));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_modifiers() {
+        assert_eq!(parse_key("C-a"), Some(Key::Ctrl('a')));
+        assert_eq!(parse_key("C-A"), Some(Key::Ctrl('a')));
+        assert_eq!(parse_key("M-f"), Some(Key::Alt('f')));
+        assert_eq!(parse_key("M-backspace"), Some(Key::Alt('\x7f')));
+    }
+
+    #[test]
+    fn parse_named_keys() {
+        assert_eq!(parse_key("up"), Some(Key::Up));
+        assert_eq!(parse_key("tab"), Some(Key::Char('\t')));
+        assert_eq!(parse_key("enter"), Some(Key::Char('\n')));
+        assert_eq!(parse_key("x"), Some(Key::Char('x')));
+    }
+
+    #[test]
+    fn parse_invalid_keys() {
+        assert_eq!(parse_key(""), None);
+        assert_eq!(parse_key("C-"), None);
+        assert_eq!(parse_key("C-ab"), None);
+        assert_eq!(parse_key("nope"), None);
+    }
+
+    #[test]
+    fn action_names() {
+        assert_eq!(Action::from_name("yank-pop"), Some(Action::YankPop));
+        assert_eq!(Action::from_name("nope"), None);
+    }
+}
@@ -0,0 +1,149 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+/// The line being edited and the cursor position within it. The line is kept
+/// as grapheme clusters so the cursor never splits a character.
+pub struct Line {
+    graphemes: Vec<String>,
+    cursor: usize,
+}
+
+fn segment(text: &str) -> Vec<String> {
+    text.graphemes(true).map(str::to_owned).collect()
+}
+
+fn is_word(g: &str) -> bool {
+    matches!(g.chars().next(), Some(c) if c.is_alphanumeric())
+}
+
+fn is_blank(g: &str) -> bool {
+    g.chars().all(char::is_whitespace)
+}
+
+impl Line {
+    pub fn new() -> Self {
+        Line {
+            graphemes: Vec::new(),
+            cursor: 0,
+        }
+    }
+
+    pub fn graphemes(&self) -> &[String] {
+        &self.graphemes
+    }
+
+    pub fn text(&self) -> String {
+        self.graphemes.concat()
+    }
+
+    /// Text before the cursor.
+    pub fn before_cursor(&self) -> String {
+        self.graphemes[..self.cursor].concat()
+    }
+
+    pub fn len(&self) -> usize {
+        self.graphemes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.graphemes.is_empty()
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn set_cursor(&mut self, pos: usize) {
+        self.cursor = pos.min(self.graphemes.len());
+    }
+
+    /// Replace the whole line and move the cursor to the end.
+    pub fn set_text(&mut self, text: &str) {
+        self.graphemes = segment(text);
+        self.cursor = self.graphemes.len();
+    }
+
+    /// Insert text at the cursor.
+    pub fn insert_str(&mut self, text: &str) {
+        // Segment the whole line again so combining marks join the character
+        // before them.
+        let mut head = self.before_cursor();
+        head.push_str(text);
+        let mut whole = head.clone();
+        whole.push_str(&self.graphemes[self.cursor..].concat());
+
+        let graphemes = segment(&whole);
+        let mut bytes = 0;
+        self.cursor = graphemes
+            .iter()
+            .take_while(|g| {
+                bytes += g.len();
+                bytes <= head.len()
+            })
+            .count();
+        self.graphemes = graphemes;
+    }
+
+    /// Remove the text between two positions and return it. The cursor is moved
+    /// to the start of the removed text.
+    pub fn remove(&mut self, start: usize, end: usize) -> String {
+        let end = end.min(self.graphemes.len());
+        let start = start.min(end);
+        let removed = self.graphemes.drain(start..end).collect();
+        self.cursor = start;
+        removed
+    }
+
+    /// Swap the characters before and at the cursor. At the end of the
+    /// line the two characters before the cursor are swapped.
+    pub fn transpose(&mut self) {
+        let len = self.graphemes.len();
+        if len < 2 || self.cursor == 0 {
+            return;
+        }
+
+        let pos = if self.cursor == len {
+            self.cursor - 1
+        } else {
+            self.cursor
+        };
+        self.graphemes.swap(pos - 1, pos);
+        self.cursor = (pos + 1).min(len);
+    }
+
+    /// Start of the word before the cursor. Words are alphanumeric runs.
+    pub fn prev_word_start(&self) -> usize {
+        let mut pos = self.cursor;
+        while pos > 0 && !is_word(&self.graphemes[pos - 1]) {
+            pos -= 1;
+        }
+        while pos > 0 && is_word(&self.graphemes[pos - 1]) {
+            pos -= 1;
+        }
+        pos
+    }
+
+    /// End of the word after the cursor.
+    pub fn next_word_end(&self) -> usize {
+        let len = self.graphemes.len();
+        let mut pos = self.cursor;
+        while pos < len && !is_word(&self.graphemes[pos]) {
+            pos += 1;
+        }
+        while pos < len && is_word(&self.graphemes[pos]) {
+            pos += 1;
+        }
+        pos
+    }
+
+    /// Start of the whitespace delimited word before the cursor.
+    pub fn prev_bigword_start(&self) -> usize {
+        let mut pos = self.cursor;
+        while pos > 0 && is_blank(&self.graphemes[pos - 1]) {
+            pos -= 1;
+        }
+        while pos > 0 && !is_blank(&self.graphemes[pos - 1]) {
+            pos -= 1;
+        }
+        pos
+    }
+}
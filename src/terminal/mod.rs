@@ -1,3 +1,19 @@
+mod completion;
+mod keymap;
+mod line;
+mod render;
+mod search;
+mod vi;
+mod width;
+
+pub use completion::Completer;
+pub use keymap::{bind_key, set_edit_mode};
+
+use keymap::{Action, EditMode};
+use line::Line;
+use render::Screen;
+use vi::ViState;
+
 use std::fs::{File, OpenOptions};
 use std::io::{self, prelude::*, stdin, stdout, BufReader, Write};
 use std::path::{Path, PathBuf};
@@ -6,13 +22,15 @@ use termion::event::Key;
 use termion::input::TermRead;
 use termion::raw::IntoRawMode;
 
-const INPUT_BUF_SIZE: usize = 1024;
 const MAX_HISTORY_ITEMS: usize = 10000;
+const KILL_RING_SIZE: usize = 10;
 
 pub struct Terminal {
     history: Vec<String>,
     history_item: usize, // Index into history
     history_file: PathBuf,
+    kill_ring: Vec<String>,
+    vi: ViState,
 }
 
 impl Terminal {
@@ -21,6 +39,8 @@ impl Terminal {
             history: Vec::with_capacity(10),
             history_item: 0,
             history_file: history_file.as_ref().to_owned(),
+            kill_ring: Vec::new(),
+            vi: ViState::new(),
         }
     }
 
@@ -67,222 +87,270 @@ impl Terminal {
     }
 
     #[allow(clippy::cognitive_complexity)]
-    pub fn readline(&mut self, prompt: &str) -> String {
-        let mut stdout = stdout()
-            .into_raw_mode()
-            .expect("Failed to enable raw mode on std input");
-
-        let mut buf = vec![0 as char; INPUT_BUF_SIZE];
-        let mut buf_len = 0;
-        let mut cursor_position = 0;
-
-        write!(stdout, "{}", prompt).unwrap();
-        stdout.flush().unwrap();
-
-        for c in stdin().keys() {
-            match c.unwrap() {
-                Key::Char(c) => {
-                    if (c as u8) == 0x0A || (c as u8) == 0x0D {
-                        write!(stdout, "\n\r").unwrap();
-                        stdout.flush().unwrap();
-                        self.history_item = self.history.len();
-                        break;
-                    }
-
-                    if cursor_position == buf_len {
-                        buf[cursor_position] = c;
-
-                        if buf_len < INPUT_BUF_SIZE {
-                            buf_len += 1;
-                        }
+    /// Read a line of input. Returns an error of kind `Interrupted` if the line
+    /// was cancelled with Ctrl-C and `UnexpectedEof` on Ctrl-D with an empty line.
+    pub fn readline(&mut self, prompt: &str, completer: &mut dyn Completer) -> io::Result<String> {
+        let mut stdout = stdout().into_raw_mode()?;
+
+        let mut line = Line::new();
+        let mut screen = Screen::new();
+        // Only the last line of a multi-line prompt is redrawn
+        let prompt_tail = prompt.rsplit('\n').next().unwrap_or_default();
+        let mut last_action = None;
+        let mut yank_len = 0;
+        let mut yank_index = 0;
+
+        let vi_mode = keymap::edit_mode() == EditMode::Vi;
+        if vi_mode {
+            self.vi.reset();
+        }
 
-                        write!(stdout, "{}", c).unwrap();
-                    } else {
-                        for i in (cursor_position..=buf_len).rev() {
-                            if i == 0 {
-                                buf[i] = 0 as char;
-                            } else {
-                                buf[i] = buf[i - 1];
-                            }
-                        }
-                        buf[cursor_position] = c;
-                        buf_len += 1;
+        write!(stdout, "{}", prompt)?;
+        screen.draw(&mut stdout, &self.mode_prompt(prompt_tail, vi_mode), &line)?;
+        stdout.flush()?;
+
+        let mut keys = stdin().keys();
+        while let Some(key) = keys.next() {
+            let key = key?;
+
+            let action = if vi_mode && self.vi.mode() == vi::Mode::Normal {
+                match self.vi.normal(key, &mut line) {
+                    Some(action) => action,
+                    None => {
+                        last_action = None;
+                        screen.draw(&mut stdout, &self.mode_prompt(prompt_tail, vi_mode), &line)?;
+                        stdout.flush()?;
+                        continue;
+                    }
+                }
+            } else if vi_mode && key == Key::Esc {
+                self.vi.finish_insert(&mut line);
+                last_action = None;
+                screen.draw(&mut stdout, &self.mode_prompt(prompt_tail, vi_mode), &line)?;
+                stdout.flush()?;
+                continue;
+            } else {
+                if vi_mode {
+                    self.vi.record(key);
+                }
 
-                        if cursor_position > 0 {
-                            write!(stdout, "{}", termion::cursor::Left(cursor_position as u16))
-                                .unwrap();
+                match keymap::lookup(key) {
+                    Some(action) => action,
+                    None => {
+                        if let Key::Char(c) = key {
+                            line.insert_str(c.encode_utf8(&mut [0; 4]));
+                            screen.draw(
+                                &mut stdout,
+                                &self.mode_prompt(prompt_tail, vi_mode),
+                                &line,
+                            )?;
+                            stdout.flush()?;
                         }
-
-                        let cursor_offset = if cursor_position == 0 {
-                            buf_len - cursor_position
-                        } else {
-                            buf_len - cursor_position - 1
-                        };
-
-                        write!(
-                            stdout,
-                            "{}{}",
-                            buf.iter().collect::<String>(),
-                            termion::cursor::Left((cursor_offset) as u16),
-                        )
-                        .unwrap();
+                        last_action = None;
+                        continue;
                     }
-                    cursor_position += 1;
                 }
-                Key::Ctrl(c) => {
-                    if c == 'c' {
-                        buf_len = 0;
-                        cursor_position = 0;
-                        self.history_item = self.history.len();
-                        write!(stdout, "\n\r\u{001b}[2K{}", prompt).unwrap();
-                    }
+            };
+
+            let cursor = line.cursor();
+            let continues_kill = matches!(last_action, Some(a) if Action::is_kill(a));
+
+            match action {
+                Action::AcceptLine => {
+                    screen.move_to_end(&mut stdout)?;
+                    write!(stdout, "\n\r")?;
+                    stdout.flush()?;
+                    self.history_item = self.history.len();
+                    return Ok(line.text());
                 }
-                Key::Up => {
-                    if self.history_item > 0 {
-                        let item = &self.history[self.history_item - 1];
-                        write!(stdout, "\r\u{001b}[2K{}{}", prompt, item).unwrap();
-                        self.history_item -= 1;
-                        buf_len = 0;
-                        cursor_position = 0;
-                        for c in item.chars() {
-                            buf[cursor_position] = c;
-                            buf_len += 1;
-                            cursor_position += 1;
-                        }
+                Action::Interrupt => {
+                    screen.move_to_end(&mut stdout)?;
+                    write!(stdout, "\n\r")?;
+                    stdout.flush()?;
+                    self.history_item = self.history.len();
+                    return Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"));
+                }
+                Action::Complete => match completion::complete(&line.before_cursor(), completer) {
+                    completion::Completion::Replace(new_before) => {
+                        line.remove(0, cursor);
+                        line.insert_str(&new_before);
+                    }
+                    completion::Completion::List(candidates) => {
+                        screen.move_to_end(&mut stdout)?;
+                        completion::print_list(&mut stdout, &candidates)?;
+                        write!(stdout, "{}", prompt)?;
+                    }
+                    completion::Completion::None => {}
+                },
+                Action::BeginningOfLine => line.set_cursor(0),
+                Action::EndOfLine => line.set_cursor(line.len()),
+                Action::BackwardChar => line.set_cursor(cursor.saturating_sub(1)),
+                Action::ForwardChar => line.set_cursor(cursor + 1),
+                Action::BackwardWord => line.set_cursor(line.prev_word_start()),
+                Action::ForwardWord => line.set_cursor(line.next_word_end()),
+                Action::BackwardDeleteChar => {
+                    if cursor > 0 {
+                        line.remove(cursor - 1, cursor);
                     }
                 }
-                Key::Down => {
-                    if self.history_item + 1 < self.history.len() {
-                        let item = &self.history[self.history_item + 1];
-                        write!(stdout, "\r\u{001b}[2K{}{}", prompt, item).unwrap();
-                        self.history_item += 1;
-                        buf_len = 0;
-                        cursor_position = 0;
-                        for c in item.chars() {
-                            buf[cursor_position] = c;
-                            buf_len += 1;
-                            cursor_position += 1;
-                        }
-                    } else {
-                        buf_len = 0;
-                        cursor_position = 0;
+                Action::DeleteChar => {
+                    line.remove(cursor, cursor + 1);
+                }
+                Action::DeleteCharOrEof => {
+                    if line.is_empty() {
+                        screen.move_to_end(&mut stdout)?;
+                        write!(stdout, "\n\r")?;
+                        stdout.flush()?;
                         self.history_item = self.history.len();
-                        write!(stdout, "\r\u{001b}[2K{}", prompt).unwrap();
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "end of input"));
                     }
+                    line.remove(cursor, cursor + 1);
                 }
-                Key::Left => {
-                    if cursor_position > 0 {
-                        write!(stdout, "\u{001b}[1D").unwrap();
-                        cursor_position -= 1;
-                    }
+                Action::KillLine => {
+                    let text = line.remove(cursor, line.len());
+                    line.set_cursor(cursor);
+                    self.kill(text, false, continues_kill);
+                }
+                Action::UnixLineDiscard => {
+                    let text = line.remove(0, cursor);
+                    self.kill(text, true, continues_kill);
+                }
+                Action::UnixWordRubout => {
+                    let text = line.remove(line.prev_bigword_start(), cursor);
+                    self.kill(text, true, continues_kill);
                 }
-                Key::Right => {
-                    if cursor_position < buf_len {
-                        write!(stdout, "\u{001b}[1C").unwrap();
-                        cursor_position += 1;
+                Action::KillWord => {
+                    let text = line.remove(cursor, line.next_word_end());
+                    self.kill(text, false, continues_kill);
+                }
+                Action::BackwardKillWord => {
+                    let text = line.remove(line.prev_word_start(), cursor);
+                    self.kill(text, true, continues_kill);
+                }
+                Action::Yank => {
+                    if let Some(text) = self.kill_ring.last() {
+                        line.insert_str(text);
+                        yank_len = line.cursor() - cursor;
+                        yank_index = 0;
                     }
                 }
-                Key::Backspace => {
-                    if buf_len > 0 {
-                        if cursor_position == buf_len {
-                            buf_len -= 1;
-                            cursor_position -= 1;
-                            buf[buf_len] = 0 as char;
-                            write!(
-                                stdout,
-                                "{} {}",
-                                termion::cursor::Left(1),
-                                termion::cursor::Left(1)
-                            )
-                            .unwrap();
-                        } else {
-                            for i in cursor_position - 1..buf_len {
-                                buf[i] = buf[i + 1]
-                            }
-                            buf_len -= 1;
-                            buf[buf_len] = 0 as char;
-
-                            write!(
-                                stdout,
-                                "{}{} {}",
-                                termion::cursor::Left(cursor_position as u16),
-                                buf.iter().collect::<String>(),
-                                termion::cursor::Left((buf_len - cursor_position + 2) as u16),
-                            )
-                            .unwrap();
-
-                            cursor_position -= 1;
-                        }
+                Action::YankPop => {
+                    let yanked = matches!(last_action, Some(Action::Yank) | Some(Action::YankPop));
+                    if yanked && !self.kill_ring.is_empty() {
+                        line.remove(cursor - yank_len, cursor);
+                        yank_index = (yank_index + 1) % self.kill_ring.len();
+                        let start = line.cursor();
+                        line.insert_str(&self.kill_ring[self.kill_ring.len() - 1 - yank_index]);
+                        yank_len = line.cursor() - start;
+                    } else {
+                        // Only valid directly after a yank
+                        last_action = None;
+                        continue;
                     }
                 }
-                Key::Delete => {
-                    if buf_len > 0 {
-                        if cursor_position == buf_len - 1 {
-                            buf[buf_len] = 0 as char;
-                            buf_len -= 1;
-                            write!(stdout, " {}", termion::cursor::Left(1),).unwrap();
-                        } else {
-                            for i in cursor_position..buf_len {
-                                buf[i] = buf[i + 1]
-                            }
-                            buf_len -= 1;
-                            buf[buf_len] = 0 as char;
-
-                            if cursor_position == 0 {
-                                write!(
-                                    stdout,
-                                    "{} {}",
-                                    buf.iter().collect::<String>(),
-                                    termion::cursor::Left((buf_len + 1) as u16),
-                                )
-                                .unwrap();
-                            } else {
-                                write!(
-                                    stdout,
-                                    "{}{} {}",
-                                    termion::cursor::Left(cursor_position as u16),
-                                    buf.iter().collect::<String>(),
-                                    termion::cursor::Left((buf_len - cursor_position + 1) as u16),
-                                )
-                                .unwrap();
-                            }
-                        }
+                Action::TransposeChars => line.transpose(),
+                Action::ClearScreen => {
+                    write!(
+                        stdout,
+                        "{}{}{}",
+                        termion::clear::All,
+                        termion::cursor::Goto(1, 1),
+                        prompt
+                    )?;
+                    screen.reset();
+                }
+                Action::PreviousHistory => {
+                    if self.history_item > 0 {
+                        self.history_item -= 1;
+                        line.set_text(&self.history[self.history_item]);
                     }
                 }
-                Key::Home => {
-                    if cursor_position > 0 {
-                        write!(stdout, "{}", termion::cursor::Left(cursor_position as u16))
-                            .unwrap();
-                        cursor_position = 0;
+                Action::NextHistory => {
+                    if self.history_item + 1 < self.history.len() {
+                        self.history_item += 1;
+                        line.set_text(&self.history[self.history_item]);
+                    } else {
+                        self.history_item = self.history.len();
+                        line.set_text("");
                     }
                 }
-                Key::End => {
-                    if cursor_position < buf_len {
-                        write!(
-                            stdout,
-                            "{}",
-                            termion::cursor::Right((buf_len - cursor_position) as u16)
-                        )
-                        .unwrap();
-                        cursor_position = buf_len;
+                Action::ReverseSearchHistory | Action::ForwardSearchHistory => {
+                    let reverse = action == Action::ReverseSearchHistory;
+                    screen.clear(&mut stdout)?;
+                    match search::search(&mut stdout, &mut keys, &self.history, reverse)? {
+                        search::SearchResult::Accept(text) => line.set_text(&text),
+                        search::SearchResult::Submit(text) => {
+                            line.set_text(&text);
+                            screen.draw(
+                                &mut stdout,
+                                &self.mode_prompt(prompt_tail, vi_mode),
+                                &line,
+                            )?;
+                            screen.move_to_end(&mut stdout)?;
+                            write!(stdout, "\n\r")?;
+                            stdout.flush()?;
+                            self.history_item = self.history.len();
+                            return Ok(line.text());
+                        }
+                        search::SearchResult::Cancel => {}
                     }
                 }
-                _ => {}
             }
-            stdout.flush().unwrap();
+
+            last_action = Some(action);
+            if vi_mode {
+                self.vi.clamp_cursor(&mut line);
+            }
+            screen.draw(&mut stdout, &self.mode_prompt(prompt_tail, vi_mode), &line)?;
+            stdout.flush()?;
         }
 
-        let line: String = buf[..buf_len].iter().collect();
+        Ok(line.text())
+    }
 
-        if line.len() > 0 {
-            self.history.push(line.clone());
-            self.history_item += 1;
-            if let Err(e) = self.write_history_line(&line) {
-                eprintln!("{}", e);
+    /// The redrawn part of the prompt, prefixed with the mode in vi mode.
+    fn mode_prompt(&self, prompt_tail: &str, vi_mode: bool) -> String {
+        if vi_mode {
+            format!("{}{}", self.vi.indicator(), prompt_tail)
+        } else {
+            prompt_tail.to_owned()
+        }
+    }
+
+    /// Add killed text to the kill ring. Consecutive kills are joined into one entry.
+    fn kill(&mut self, text: String, prepend: bool, continued: bool) {
+        if text.is_empty() {
+            return;
+        }
+
+        match self.kill_ring.last_mut() {
+            Some(last) if continued => {
+                if prepend {
+                    last.insert_str(0, &text);
+                } else {
+                    last.push_str(&text);
+                }
+            }
+            _ => {
+                self.kill_ring.push(text);
+                if self.kill_ring.len() > KILL_RING_SIZE {
+                    self.kill_ring.remove(0);
+                }
             }
         }
+    }
 
-        line
+    /// Add an entry to the history list and history file.
+    pub fn add_history(&mut self, line: &str) {
+        if line.is_empty() {
+            return;
+        }
+
+        self.history.push(line.to_owned());
+        self.history_item = self.history.len();
+        if let Err(e) = self.write_history_line(line) {
+            eprintln!("{}", e);
+        }
     }
 }
 
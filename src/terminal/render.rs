@@ -0,0 +1,100 @@
+use std::io::{self, Write};
+
+use super::line::Line;
+use super::width::{display_width, grapheme_width};
+
+/// Draws the prompt and line, wrapping across rows at the terminal width. The
+/// row the cursor was left on is remembered so the next draw can start over
+/// from the first row.
+pub struct Screen {
+    cursor_row: usize,
+    end_row: usize,
+}
+
+impl Screen {
+    pub fn new() -> Self {
+        Screen {
+            cursor_row: 0,
+            end_row: 0,
+        }
+    }
+
+    /// Forget the drawn line. The cursor is at the start of a new prompt row.
+    pub fn reset(&mut self) {
+        self.cursor_row = 0;
+        self.end_row = 0;
+    }
+
+    /// Clear the drawn line, leaving the cursor at the start of its first row.
+    pub fn clear<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        if self.cursor_row > 0 {
+            write!(out, "{}", termion::cursor::Up(self.cursor_row as u16))?;
+        }
+        write!(out, "\r{}", termion::clear::AfterCursor)?;
+        self.reset();
+        Ok(())
+    }
+
+    /// Move the cursor to the last row of the line so output can follow it.
+    pub fn move_to_end<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        if self.end_row > self.cursor_row {
+            let rows = self.end_row - self.cursor_row;
+            write!(out, "{}", termion::cursor::Down(rows as u16))?;
+        }
+        self.reset();
+        Ok(())
+    }
+
+    pub fn draw<W: Write>(&mut self, out: &mut W, prompt: &str, line: &Line) -> io::Result<()> {
+        let cols = termion::terminal_size()
+            .map(|(w, _)| w as usize)
+            .unwrap_or(80)
+            .max(1);
+
+        self.clear(out)?;
+        write!(out, "{}{}", prompt, line.text())?;
+
+        // Find the row and column of the cursor and the end of the line. A
+        // wide character that doesn't fit at the end of a row moves to the next.
+        let prompt_width = display_width(prompt);
+        let mut row = prompt_width / cols;
+        let mut col = prompt_width % cols;
+        let mut cursor = (row, col);
+        for (i, g) in line.graphemes().iter().enumerate() {
+            if i == line.cursor() {
+                cursor = (row, col);
+            }
+            let width = grapheme_width(g);
+            if col + width > cols {
+                row += 1;
+                col = 0;
+            }
+            col += width;
+            if col == cols {
+                row += 1;
+                col = 0;
+            }
+        }
+        if line.cursor() == line.len() {
+            cursor = (row, col);
+        }
+
+        // The terminal doesn't wrap until another character is written when
+        // the text ends exactly at the edge, so start the next row ourselves.
+        if col == 0 && row > 0 {
+            write!(out, "\r\n")?;
+        }
+
+        if row > cursor.0 {
+            write!(out, "{}", termion::cursor::Up((row - cursor.0) as u16))?;
+        }
+        write!(out, "\r")?;
+        if cursor.1 > 0 {
+            write!(out, "{}", termion::cursor::Right(cursor.1 as u16))?;
+        }
+
+        self.cursor_row = cursor.0;
+        self.end_row = row;
+        Ok(())
+    }
+}
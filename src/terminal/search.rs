@@ -0,0 +1,145 @@
+use std::io::{self, Write};
+
+use termion::event::Key;
+
+pub enum SearchResult {
+    /// Place the matched line in the editor
+    Accept(String),
+    /// Run the matched line
+    Submit(String),
+    Cancel,
+}
+
+struct HistorySearch<'a> {
+    history: &'a [String],
+    query: String,
+    reverse: bool,
+    // Index of the current match in history
+    current: Option<usize>,
+    failed: bool,
+}
+
+impl<'a> HistorySearch<'a> {
+    fn find(&mut self, reverse_below: usize, forward_from: usize) {
+        let found = if self.reverse {
+            (0..reverse_below.min(self.history.len()))
+                .rev()
+                .find(|i| self.history[*i].contains(&self.query))
+        } else {
+            (forward_from..self.history.len()).find(|i| self.history[*i].contains(&self.query))
+        };
+
+        self.failed = found.is_none();
+        if found.is_some() {
+            self.current = found;
+        }
+    }
+
+    /// The query changed, the current match is checked first.
+    fn research(&mut self) {
+        if self.query.is_empty() {
+            self.current = None;
+            self.failed = false;
+            return;
+        }
+
+        let len = self.history.len();
+        match self.current {
+            Some(i) => self.find(i + 1, i),
+            None => self.find(len, 0),
+        }
+    }
+
+    /// Move to the next match in the given direction.
+    fn next(&mut self, reverse: bool) {
+        self.reverse = reverse;
+        if self.query.is_empty() {
+            return;
+        }
+
+        let len = self.history.len();
+        match self.current {
+            Some(i) => self.find(i, i + 1),
+            None => self.find(len, 0),
+        }
+    }
+
+    fn matched_line(&self) -> String {
+        self.current
+            .map(|i| self.history[i].clone())
+            .unwrap_or_default()
+    }
+
+    fn draw<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        write!(
+            out,
+            "\r\u{001b}[2K({}{}i-search)`{}': ",
+            if self.failed { "failed " } else { "" },
+            if self.reverse { "reverse-" } else { "" },
+            self.query
+        )?;
+
+        let line = self.matched_line();
+        match line.find(&self.query) {
+            Some(start) if !self.query.is_empty() => {
+                let end = start + self.query.len();
+                write!(
+                    out,
+                    "{}{}{}{}{}",
+                    &line[..start],
+                    termion::style::Invert,
+                    &line[start..end],
+                    termion::style::Reset,
+                    &line[end..]
+                )?;
+            }
+            _ => write!(out, "{}", line)?,
+        }
+
+        out.flush()
+    }
+}
+
+/// Run an incremental history search, reading keys until the search is accepted or cancelled.
+pub fn search<W, I>(
+    out: &mut W,
+    keys: &mut I,
+    history: &[String],
+    reverse: bool,
+) -> io::Result<SearchResult>
+where
+    W: Write,
+    I: Iterator<Item = io::Result<Key>>,
+{
+    let mut state = HistorySearch {
+        history,
+        query: String::new(),
+        reverse,
+        current: None,
+        failed: false,
+    };
+    state.draw(out)?;
+
+    for key in keys {
+        match key? {
+            Key::Char('\n') | Key::Char('\r') => {
+                return Ok(SearchResult::Submit(state.matched_line()));
+            }
+            Key::Char(c) => {
+                state.query.push(c);
+                state.research();
+            }
+            Key::Backspace => {
+                state.query.pop();
+                state.research();
+            }
+            Key::Ctrl('r') => state.next(true),
+            Key::Ctrl('s') => state.next(false),
+            Key::Esc | Key::Ctrl('g') | Key::Ctrl('c') => return Ok(SearchResult::Cancel),
+            _ => return Ok(SearchResult::Accept(state.matched_line())),
+        }
+        state.draw(out)?;
+    }
+
+    Ok(SearchResult::Cancel)
+}
@@ -0,0 +1,668 @@
+use std::mem;
+
+use termion::event::Key;
+
+use super::keymap::Action;
+use super::line::Line;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Insert,
+    Normal,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Delete,
+    Change,
+    Yank,
+}
+
+#[derive(Clone, Copy)]
+enum Motion {
+    Left,
+    Right,
+    // The flag selects whitespace delimited WORDs
+    WordForward(bool),
+    WordBackward(bool),
+    WordEnd(bool),
+    LineStart,
+    FirstNonBlank,
+    LineEnd,
+    Find { ch: char, forward: bool, till: bool },
+}
+
+enum InsertAt {
+    Cursor,
+    After,
+    Start,
+    End,
+}
+
+enum Command {
+    Move(Motion),
+    // A missing motion operates on the whole line (dd, cc, yy)
+    Operate(Operator, Option<Motion>),
+    Insert(InsertAt),
+    Put { before: bool },
+    Replace(char),
+    ToggleCase,
+    Undo,
+    Repeat,
+    Editor(Action),
+}
+
+// Larger counts are clamped, they're only useful for repeating a put and a
+// huge one would try to allocate gigabytes.
+const MAX_COUNT: usize = 1000;
+
+enum Parsed<T> {
+    Incomplete,
+    Invalid,
+    Done(T),
+}
+
+/// Modal editing state. Normal mode keys are collected until they form a
+/// complete command.
+pub struct ViState {
+    mode: Mode,
+    pending: Vec<Key>,
+    register: String,
+    undo: Vec<(String, usize)>,
+    // Keys of the last change for `.`, including text typed in insert mode
+    last_change: Vec<Key>,
+    recording: Option<Vec<Key>>,
+}
+
+impl ViState {
+    pub fn new() -> Self {
+        ViState {
+            mode: Mode::Insert,
+            pending: Vec::new(),
+            register: String::new(),
+            undo: Vec::new(),
+            last_change: Vec::new(),
+            recording: None,
+        }
+    }
+
+    /// Prepare for a new line. Lines start in insert mode.
+    pub fn reset(&mut self) {
+        self.mode = Mode::Insert;
+        self.pending.clear();
+        self.undo.clear();
+        self.recording = None;
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn indicator(&self) -> &'static str {
+        match self.mode {
+            Mode::Insert => "(ins) ",
+            Mode::Normal => "(cmd) ",
+        }
+    }
+
+    /// Record a key typed in insert mode so the change can be repeated.
+    pub fn record(&mut self, key: Key) {
+        if let Some(keys) = &mut self.recording {
+            keys.push(key);
+        }
+    }
+
+    /// Leave insert mode.
+    pub fn finish_insert(&mut self, line: &mut Line) {
+        self.mode = Mode::Normal;
+        if let Some(mut keys) = self.recording.take() {
+            keys.push(Key::Esc);
+            self.last_change = keys;
+        }
+        self.drop_unchanged_undo(line);
+        line.set_cursor(line.cursor().saturating_sub(1));
+    }
+
+    /// In normal mode the cursor sits on a character, never past the end.
+    pub fn clamp_cursor(&self, line: &mut Line) {
+        if self.mode == Mode::Normal && line.cursor() >= line.len() {
+            line.set_cursor(line.len().saturating_sub(1));
+        }
+    }
+
+    /// Handle a key in normal mode. Returns an action for the editor to run
+    /// if the command maps to one.
+    pub fn normal(&mut self, key: Key, line: &mut Line) -> Option<Action> {
+        self.feed(key, line, false)
+    }
+
+    fn feed(&mut self, key: Key, line: &mut Line, replaying: bool) -> Option<Action> {
+        self.pending.push(key);
+        let (count, command) = match parse(&self.pending) {
+            Parsed::Incomplete => return None,
+            Parsed::Invalid => {
+                self.pending.clear();
+                return None;
+            }
+            Parsed::Done(parsed) => parsed,
+        };
+        let keys = mem::take(&mut self.pending);
+
+        match command {
+            Command::Editor(action) => return Some(action),
+            Command::Undo => {
+                if let Some((text, cursor)) = self.undo.pop() {
+                    line.set_text(&text);
+                    line.set_cursor(cursor);
+                }
+            }
+            Command::Repeat => {
+                if !replaying {
+                    let keys = self.last_change.clone();
+                    self.replay(&keys, line);
+                }
+            }
+            Command::Move(motion) => {
+                if let Some(pos) = motion.target(line.graphemes(), line.cursor(), count) {
+                    line.set_cursor(pos);
+                }
+            }
+            command => {
+                self.undo.push((line.text(), line.cursor()));
+                self.change(count, command, line);
+
+                if self.mode == Mode::Insert {
+                    if !replaying {
+                        self.recording = Some(keys);
+                    }
+                } else if !self.drop_unchanged_undo(line) && !replaying {
+                    self.last_change = keys;
+                }
+            }
+        }
+
+        self.clamp_cursor(line);
+        None
+    }
+
+    /// Run the keys of a previous change again.
+    fn replay(&mut self, keys: &[Key], line: &mut Line) {
+        for key in keys {
+            match self.mode {
+                Mode::Normal => {
+                    self.feed(*key, line, true);
+                }
+                Mode::Insert => match key {
+                    Key::Esc => self.finish_insert(line),
+                    Key::Char(c) => {
+                        line.insert_str(c.encode_utf8(&mut [0; 4]));
+                    }
+                    Key::Backspace => {
+                        let cursor = line.cursor();
+                        if cursor > 0 {
+                            line.remove(cursor - 1, cursor);
+                        }
+                    }
+                    _ => {}
+                },
+            }
+        }
+    }
+
+    /// Remove the last undo entry if the line wasn't changed since it was taken.
+    fn drop_unchanged_undo(&mut self, line: &Line) -> bool {
+        match self.undo.last() {
+            Some((text, _)) if *text == line.text() => {
+                self.undo.pop();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn change(&mut self, count: usize, command: Command, line: &mut Line) {
+        let cursor = line.cursor();
+        let len = line.len();
+
+        match command {
+            Command::Insert(at) => {
+                let pos = match at {
+                    InsertAt::Cursor => cursor,
+                    InsertAt::After => cursor + 1,
+                    InsertAt::Start => first_non_blank(line.graphemes()),
+                    InsertAt::End => len,
+                };
+                line.set_cursor(pos);
+                self.mode = Mode::Insert;
+            }
+            Command::Operate(op, motion) => {
+                let (start, end) = match motion {
+                    None => (0, len),
+                    Some(motion) => match operator_range(op, motion, line, count) {
+                        Some(range) => range,
+                        None => return,
+                    },
+                };
+
+                let text = line.graphemes()[start..end].concat();
+                match op {
+                    Operator::Delete => {
+                        line.remove(start, end);
+                    }
+                    Operator::Change => {
+                        line.remove(start, end);
+                        self.mode = Mode::Insert;
+                    }
+                    Operator::Yank => line.set_cursor(start),
+                }
+                if !text.is_empty() {
+                    self.register = text;
+                }
+            }
+            Command::Put { before } => {
+                if self.register.is_empty() {
+                    return;
+                }
+                if !before && !line.is_empty() {
+                    line.set_cursor(cursor + 1);
+                }
+                line.insert_str(&self.register.repeat(count));
+                line.set_cursor(line.cursor().saturating_sub(1));
+            }
+            Command::Replace(c) => {
+                if cursor + count > len {
+                    return;
+                }
+                line.remove(cursor, cursor + count);
+                line.insert_str(&c.to_string().repeat(count));
+                line.set_cursor(cursor + count - 1);
+            }
+            Command::ToggleCase => {
+                let text: String = line
+                    .remove(cursor, cursor + count)
+                    .chars()
+                    .map(|c| {
+                        if c.is_uppercase() {
+                            c.to_lowercase().next().unwrap_or(c)
+                        } else {
+                            c.to_uppercase().next().unwrap_or(c)
+                        }
+                    })
+                    .collect();
+                line.insert_str(&text);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn operator_range(
+    op: Operator,
+    motion: Motion,
+    line: &Line,
+    count: usize,
+) -> Option<(usize, usize)> {
+    let text = line.graphemes();
+    let cursor = line.cursor();
+    let len = text.len();
+
+    // cw changes to the end of the word, like ce, but stays on a one character word
+    if let Motion::WordForward(big) = motion {
+        if op == Operator::Change && cursor < len && !is_blank(&text[cursor]) {
+            let mut end = run_end(text, cursor, big);
+            for _ in 1..count {
+                end = word_end(text, end, big);
+            }
+            return Some((cursor, (end + 1).min(len)));
+        }
+    }
+
+    let target = motion.target(text, cursor, count)?;
+    if target < cursor {
+        Some((target, cursor))
+    } else {
+        let end = if motion.inclusive() {
+            target + 1
+        } else {
+            target
+        };
+        Some((cursor, end.min(len)))
+    }
+}
+
+impl Motion {
+    fn target(self, text: &[String], cursor: usize, count: usize) -> Option<usize> {
+        let len = text.len();
+        let mut pos = cursor;
+
+        for _ in 0..count {
+            pos = match self {
+                Motion::Left => pos.saturating_sub(1),
+                Motion::Right => (pos + 1).min(len),
+                Motion::WordForward(big) => word_forward(text, pos, big),
+                Motion::WordBackward(big) => word_backward(text, pos, big),
+                Motion::WordEnd(big) => word_end(text, pos, big),
+                Motion::LineStart => 0,
+                Motion::FirstNonBlank => first_non_blank(text),
+                Motion::LineEnd => len,
+                Motion::Find { ch, forward, till } => find_char(text, pos, ch, forward, till)?,
+            };
+        }
+
+        Some(pos)
+    }
+
+    /// Operators include the character at the target of inclusive motions.
+    fn inclusive(self) -> bool {
+        match self {
+            Motion::WordEnd(_) => true,
+            Motion::Find { forward, .. } => forward,
+            _ => false,
+        }
+    }
+}
+
+fn is_blank(g: &str) -> bool {
+    g.chars().all(char::is_whitespace)
+}
+
+/// Word class of a grapheme, decided by its first character.
+fn class(g: &str, big: bool) -> u8 {
+    let c = g.chars().next().unwrap_or(' ');
+    if c.is_whitespace() {
+        0
+    } else if big || c.is_alphanumeric() || c == '_' {
+        1
+    } else {
+        2
+    }
+}
+
+fn word_forward(text: &[String], pos: usize, big: bool) -> usize {
+    let len = text.len();
+    if pos >= len {
+        return len;
+    }
+
+    let start = class(&text[pos], big);
+    let mut pos = pos;
+    if start != 0 {
+        while pos < len && class(&text[pos], big) == start {
+            pos += 1;
+        }
+    }
+    while pos < len && is_blank(&text[pos]) {
+        pos += 1;
+    }
+    pos
+}
+
+fn word_backward(text: &[String], pos: usize, big: bool) -> usize {
+    let mut pos = pos.min(text.len());
+    while pos > 0 && is_blank(&text[pos - 1]) {
+        pos -= 1;
+    }
+    if pos == 0 {
+        return 0;
+    }
+
+    let start = class(&text[pos - 1], big);
+    while pos > 0 && class(&text[pos - 1], big) == start {
+        pos -= 1;
+    }
+    pos
+}
+
+fn word_end(text: &[String], pos: usize, big: bool) -> usize {
+    let len = text.len();
+    let mut pos = pos + 1;
+    while pos < len && is_blank(&text[pos]) {
+        pos += 1;
+    }
+    if pos >= len {
+        return len.saturating_sub(1);
+    }
+    run_end(text, pos, big)
+}
+
+/// Last position of the run of characters in the same class as `pos`.
+fn run_end(text: &[String], pos: usize, big: bool) -> usize {
+    let start = class(&text[pos], big);
+    let mut pos = pos;
+    while pos + 1 < text.len() && class(&text[pos + 1], big) == start {
+        pos += 1;
+    }
+    pos
+}
+
+fn first_non_blank(text: &[String]) -> usize {
+    text.iter().position(|g| !is_blank(g)).unwrap_or(text.len())
+}
+
+fn find_char(text: &[String], pos: usize, ch: char, forward: bool, till: bool) -> Option<usize> {
+    // Skip the character next to the cursor so repeated t and T keep moving
+    let skip = till as usize;
+    let ch = ch.to_string();
+    if forward {
+        (pos + 1 + skip..text.len())
+            .find(|i| text[*i] == ch)
+            .map(|i| i - skip)
+    } else {
+        (0..pos.saturating_sub(skip))
+            .rev()
+            .find(|i| text[*i] == ch)
+            .map(|i| i + skip)
+    }
+}
+
+fn parse_count(keys: &[Key], i: &mut usize) -> Option<usize> {
+    let mut count: Option<usize> = None;
+    while let Some(Key::Char(c)) = keys.get(*i) {
+        match c.to_digit(10) {
+            Some(d) if d > 0 || count.is_some() => {
+                let n = count.unwrap_or(0).saturating_mul(10) + d as usize;
+                count = Some(n.min(MAX_COUNT));
+                *i += 1;
+            }
+            _ => break,
+        }
+    }
+    count
+}
+
+/// Parse a normal mode command: an optional count followed by a command,
+/// an operator and motion, or a motion.
+fn parse(keys: &[Key]) -> Parsed<(usize, Command)> {
+    let mut i = 0;
+    let count = parse_count(keys, &mut i).unwrap_or(1);
+    let key = match keys.get(i) {
+        Some(key) => *key,
+        None => return Parsed::Incomplete,
+    };
+
+    let command = match key {
+        Key::Char(c @ 'd') | Key::Char(c @ 'c') | Key::Char(c @ 'y') => {
+            let op = match c {
+                'd' => Operator::Delete,
+                'c' => Operator::Change,
+                _ => Operator::Yank,
+            };
+            i += 1;
+            let motion_count = parse_count(keys, &mut i).unwrap_or(1);
+            match keys.get(i) {
+                None => return Parsed::Incomplete,
+                Some(Key::Char(m)) if *m == c => Command::Operate(op, None),
+                Some(_) => match parse_motion(&keys[i..]) {
+                    Parsed::Done(motion) => {
+                        return Parsed::Done((
+                            (count * motion_count).min(MAX_COUNT),
+                            Command::Operate(op, Some(motion)),
+                        ))
+                    }
+                    Parsed::Incomplete => return Parsed::Incomplete,
+                    Parsed::Invalid => return Parsed::Invalid,
+                },
+            }
+        }
+        Key::Char('r') => match keys.get(i + 1) {
+            None => return Parsed::Incomplete,
+            Some(Key::Char(c)) => Command::Replace(*c),
+            Some(_) => return Parsed::Invalid,
+        },
+        Key::Char('i') => Command::Insert(InsertAt::Cursor),
+        Key::Char('a') => Command::Insert(InsertAt::After),
+        Key::Char('I') => Command::Insert(InsertAt::Start),
+        Key::Char('A') => Command::Insert(InsertAt::End),
+        Key::Char('x') | Key::Delete => Command::Operate(Operator::Delete, Some(Motion::Right)),
+        Key::Char('X') => Command::Operate(Operator::Delete, Some(Motion::Left)),
+        Key::Char('D') => Command::Operate(Operator::Delete, Some(Motion::LineEnd)),
+        Key::Char('C') => Command::Operate(Operator::Change, Some(Motion::LineEnd)),
+        Key::Char('s') => Command::Operate(Operator::Change, Some(Motion::Right)),
+        Key::Char('S') => Command::Operate(Operator::Change, None),
+        Key::Char('p') => Command::Put { before: false },
+        Key::Char('P') => Command::Put { before: true },
+        Key::Char('~') => Command::ToggleCase,
+        Key::Char('u') => Command::Undo,
+        Key::Char('.') => Command::Repeat,
+        Key::Char('j') | Key::Down | Key::Ctrl('n') => Command::Editor(Action::NextHistory),
+        Key::Char('k') | Key::Up | Key::Ctrl('p') => Command::Editor(Action::PreviousHistory),
+        Key::Char('\n') => Command::Editor(Action::AcceptLine),
+        Key::Ctrl('c') => Command::Editor(Action::Interrupt),
+        Key::Ctrl('d') => Command::Editor(Action::DeleteCharOrEof),
+        Key::Ctrl('l') => Command::Editor(Action::ClearScreen),
+        Key::Ctrl('r') => Command::Editor(Action::ReverseSearchHistory),
+        Key::Ctrl('s') => Command::Editor(Action::ForwardSearchHistory),
+        _ => match parse_motion(&keys[i..]) {
+            Parsed::Done(motion) => Command::Move(motion),
+            Parsed::Incomplete => return Parsed::Incomplete,
+            Parsed::Invalid => return Parsed::Invalid,
+        },
+    };
+
+    Parsed::Done((count, command))
+}
+
+fn parse_motion(keys: &[Key]) -> Parsed<Motion> {
+    let motion = match keys.first() {
+        None => return Parsed::Incomplete,
+        Some(Key::Char('h')) | Some(Key::Left) | Some(Key::Backspace) => Motion::Left,
+        Some(Key::Char('l')) | Some(Key::Char(' ')) | Some(Key::Right) => Motion::Right,
+        Some(Key::Char('w')) => Motion::WordForward(false),
+        Some(Key::Char('W')) => Motion::WordForward(true),
+        Some(Key::Char('b')) => Motion::WordBackward(false),
+        Some(Key::Char('B')) => Motion::WordBackward(true),
+        Some(Key::Char('e')) => Motion::WordEnd(false),
+        Some(Key::Char('E')) => Motion::WordEnd(true),
+        Some(Key::Char('0')) | Some(Key::Home) => Motion::LineStart,
+        Some(Key::Char('^')) => Motion::FirstNonBlank,
+        Some(Key::Char('$')) | Some(Key::End) => Motion::LineEnd,
+        Some(Key::Char(c @ 'f'))
+        | Some(Key::Char(c @ 't'))
+        | Some(Key::Char(c @ 'F'))
+        | Some(Key::Char(c @ 'T')) => match keys.get(1) {
+            None => return Parsed::Incomplete,
+            Some(Key::Char(ch)) => Motion::Find {
+                ch: *ch,
+                forward: c.is_lowercase(),
+                till: *c == 't' || *c == 'T',
+            },
+            Some(_) => return Parsed::Invalid,
+        },
+        Some(_) => return Parsed::Invalid,
+    };
+
+    Parsed::Done(motion)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(s: &str) -> Vec<Key> {
+        s.chars().map(Key::Char).collect()
+    }
+
+    #[test]
+    fn parse_counts() {
+        assert!(matches!(
+            parse(&keys("3w")),
+            Parsed::Done((3, Command::Move(Motion::WordForward(false))))
+        ));
+        assert!(matches!(
+            parse(&keys("10l")),
+            Parsed::Done((10, Command::Move(Motion::Right)))
+        ));
+        // A leading zero is a motion, not a count
+        assert!(matches!(
+            parse(&keys("0")),
+            Parsed::Done((1, Command::Move(Motion::LineStart)))
+        ));
+    }
+
+    #[test]
+    fn parse_clamps_counts() {
+        assert!(matches!(
+            parse(&keys("99999999999999999999999p")),
+            Parsed::Done((MAX_COUNT, Command::Put { before: false }))
+        ));
+        assert!(matches!(
+            parse(&keys("5000d5000w")),
+            Parsed::Done((MAX_COUNT, Command::Operate(Operator::Delete, _)))
+        ));
+    }
+
+    #[test]
+    fn parse_operators() {
+        assert!(matches!(
+            parse(&keys("dd")),
+            Parsed::Done((1, Command::Operate(Operator::Delete, None)))
+        ));
+        assert!(matches!(
+            parse(&keys("2d3W")),
+            Parsed::Done((
+                6,
+                Command::Operate(Operator::Delete, Some(Motion::WordForward(true)))
+            ))
+        ));
+        assert!(matches!(
+            parse(&keys("ct)")),
+            Parsed::Done((
+                1,
+                Command::Operate(
+                    Operator::Change,
+                    Some(Motion::Find {
+                        ch: ')',
+                        forward: true,
+                        till: true
+                    })
+                )
+            ))
+        ));
+    }
+
+    #[test]
+    fn parse_incomplete() {
+        assert!(matches!(parse(&keys("")), Parsed::Incomplete));
+        assert!(matches!(parse(&keys("2")), Parsed::Incomplete));
+        assert!(matches!(parse(&keys("d")), Parsed::Incomplete));
+        assert!(matches!(parse(&keys("df")), Parsed::Incomplete));
+        assert!(matches!(parse(&keys("r")), Parsed::Incomplete));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(matches!(parse(&keys("dq")), Parsed::Invalid));
+        assert!(matches!(parse(&keys("Q")), Parsed::Invalid));
+        assert!(matches!(parse(&[Key::Char('f'), Key::Up]), Parsed::Invalid));
+    }
+
+    #[test]
+    fn word_motions() {
+        let text: Vec<String> = "ls -la  foo.txt".chars().map(String::from).collect();
+        assert_eq!(word_forward(&text, 0, false), 3);
+        assert_eq!(word_forward(&text, 3, false), 4);
+        assert_eq!(word_forward(&text, 3, true), 8);
+        assert_eq!(word_backward(&text, 8, false), 4);
+        assert_eq!(word_end(&text, 8, true), 14);
+    }
+}
@@ -0,0 +1,78 @@
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// Terminal columns used by a grapheme cluster. Clusters made of several code
+/// points, like emoji sequences, are drawn as a single glyph.
+pub fn grapheme_width(g: &str) -> usize {
+    g.width().min(2)
+}
+
+/// Terminal columns used by text that may contain ANSI escape sequences.
+pub fn display_width(s: &str) -> usize {
+    strip_ansi(s).graphemes(true).map(grapheme_width).sum()
+}
+
+/// Remove CSI and OSC escape sequences, which take no space on screen.
+pub fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\u{001b}' {
+            out.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            // Parameters followed by a final byte in the range @ to ~
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // Terminated by BEL or ESC \
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\u{0007}' {
+                        break;
+                    }
+                    if c == '\u{001b}' {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_csi_and_osc() {
+        assert_eq!(strip_ansi("\u{1b}[1;32mok\u{1b}[0m"), "ok");
+        assert_eq!(strip_ansi("\u{1b}]0;title\u{7}$ "), "$ ");
+        assert_eq!(strip_ansi("\u{1b}]0;title\u{1b}\\$ "), "$ ");
+        assert_eq!(strip_ansi("plain"), "plain");
+    }
+
+    #[test]
+    fn grapheme_widths() {
+        assert_eq!(grapheme_width("a"), 1);
+        assert_eq!(grapheme_width("界"), 2);
+        assert_eq!(grapheme_width("e\u{301}"), 1);
+        assert_eq!(grapheme_width("👨‍👩‍👧"), 2);
+    }
+
+    #[test]
+    fn display_width_skips_escapes() {
+        assert_eq!(display_width("\u{1b}[31m世界\u{1b}[0m>"), 5);
+    }
+}
@@ -0,0 +1,65 @@
+use lazuli_vm::args_setup;
+use lazuli_vm::object::cons_list::ConsList;
+use lazuli_vm::object::Node;
+use lazuli_vm::vm::VM;
+
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+// User and system time of every child process the shell has waited for,
+// whether it was reaped by job control or when its output was captured.
+thread_local! {
+    static CHILD_TIMES: Cell<(Duration, Duration)> = const { Cell::new((Duration::ZERO, Duration::ZERO)) };
+}
+
+/// Add the resource usage of a child that has exited, from wait4.
+pub fn add_child_usage(usage: &libc::rusage) {
+    CHILD_TIMES.with(|t| {
+        let (user, sys) = t.get();
+        t.set((
+            user + timeval_duration(usage.ru_utime),
+            sys + timeval_duration(usage.ru_stime),
+        ));
+    });
+}
+
+fn children_times() -> (Duration, Duration) {
+    CHILD_TIMES.with(|t| t.get())
+}
+
+fn timeval_duration(tv: libc::timeval) -> Duration {
+    Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+}
+
+// Format like bash, 0m1.234s
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    format!("{}m{}.{:03}s", secs / 60, secs % 60, d.subsec_millis())
+}
+
+/// Evaluate forms and report the real time taken and the user and system
+/// time of the commands they ran. Times are printed to stderr even if a form
+/// fails.
+pub fn shell_time(vm: &mut VM, args: ConsList<Node>) -> Result<Node, String> {
+    let args = args_setup!(args, "time", >=, 1);
+
+    let (user_start, sys_start) = children_times();
+    let start = Instant::now();
+
+    let mut ret = Ok(Node::Empty);
+    for form in args.iter() {
+        ret = vm.eval(form);
+        if ret.is_err() {
+            break;
+        }
+    }
+
+    let real = start.elapsed();
+    let (user_end, sys_end) = children_times();
+    eprintln!();
+    eprintln!("real\t{}", format_duration(real));
+    eprintln!("user\t{}", format_duration(user_end - user_start));
+    eprintln!("sys\t{}", format_duration(sys_end - sys_start));
+
+    ret
+}
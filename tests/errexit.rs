@@ -0,0 +1,15 @@
+use std::process::Command;
+
+#[test]
+fn errexit_stops_script() {
+    let out = Command::new(env!("CARGO_BIN_EXE_lish"))
+        .arg(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/scripts/errexit.lisp"
+        ))
+        .output()
+        .unwrap();
+
+    assert_eq!(out.status.code(), Some(1));
+    assert!(!String::from_utf8_lossy(&out.stdout).contains("after"));
+}
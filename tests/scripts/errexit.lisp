@@ -0,0 +1,4 @@
+; A failing command stops the script with its status
+(set-option :errexit t)
+(false)
+(echo after)